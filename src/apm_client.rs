@@ -1,7 +1,9 @@
 use std::{
     fmt::{Display, Formatter, Result},
+    future::Future,
     ops::Deref,
-    sync::Arc,
+    sync::{mpsc, Arc},
+    time::Duration,
 };
 
 use anyhow::Result as AnyResult;
@@ -12,6 +14,7 @@ use serde_json::{json, Value};
 use std::io::Read;
 use tokio::runtime;
 use tokio::runtime::Runtime;
+use tokio::time::{self, MissedTickBehavior};
 use tracing::subscriber;
 use tracing::subscriber::NoSubscriber;
 
@@ -23,6 +26,8 @@ pub(crate) struct Batch {
     transaction: Option<Value>,
     span: Option<Value>,
     error: Option<Value>,
+    log: Option<Value>,
+    metricsets: Vec<Value>,
 }
 
 impl Display for Batch {
@@ -41,6 +46,14 @@ impl Display for Batch {
             writeln!(f, "{}", json!({ "error": error }))?;
         }
 
+        if let Some(log) = &self.log {
+            writeln!(f, "{}", json!({ "log": log }))?;
+        }
+
+        for metricset in &self.metricsets {
+            writeln!(f, "{}", json!({ "metricset": metricset }))?;
+        }
+
         Ok(())
     }
 }
@@ -57,6 +70,30 @@ impl Batch {
             transaction,
             span,
             error,
+            log: None,
+            metricsets: Vec::new(),
+        }
+    }
+
+    pub fn log(metadata: Value, log: Value) -> Self {
+        Batch {
+            metadata,
+            transaction: None,
+            span: None,
+            error: None,
+            log: Some(log),
+            metricsets: Vec::new(),
+        }
+    }
+
+    pub fn metricsets(metadata: Value, metricsets: Vec<Value>) -> Self {
+        Batch {
+            metadata,
+            transaction: None,
+            span: None,
+            error: None,
+            log: None,
+            metricsets,
         }
     }
 }
@@ -116,28 +153,92 @@ impl ApmClient {
     }
 
     pub fn send_batch(&self, batch: Batch) {
+        self.runtime.spawn(self.send(batch));
+    }
+
+    /// Sends given batch, blocking until it's sent or the timeout elapses. Useful when the process
+    /// might not survive long enough for the batch to be sent in the background.
+    pub fn send_batch_and_wait(&self, batch: Batch, timeout: Duration) {
+        self.run_and_wait(self.send(batch), timeout);
+    }
+
+    /// Runs given future on the client runtime, blocking until it completes or the timeout elapses.
+    /// Works regardless of being called from within another runtime.
+    pub fn run_and_wait<F>(&self, future: F, timeout: Duration) -> Option<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let (sender, receiver) = mpsc::channel();
+
+        self.runtime.spawn(async move {
+            let _subscriber_guard = subscriber::set_default(NoSubscriber::default());
+            let _ = sender.send(future.await);
+        });
+
+        receiver.recv_timeout(timeout).ok()
+    }
+
+    /// Periodically sends batches created by given function, if any.
+    pub fn send_periodically<F>(&self, period: Duration, create_batch: F)
+    where
+        F: Fn() -> Option<Batch> + Send + 'static,
+    {
         let client = self.client.clone();
         let apm_address = self.apm_address.clone();
         let authorization = self.authorization.clone();
 
         self.runtime.spawn(async move {
-            let _subscriber_guard = subscriber::set_default(NoSubscriber::default());
-            let mut request = client
-                .post(format!("{}/intake/v2/events", apm_address))
-                .header(
-                    header::CONTENT_TYPE,
-                    header::HeaderValue::from_static("application/x-ndjson"),
-                )
-                .body(batch.to_string());
-
-            if let Some(authorization) = &authorization {
-                request = request.header(header::AUTHORIZATION, authorization.deref());
-            }
-
-            let result = request.send().await;
-            if let Err(error) = result {
-                eprintln!("Error sending batch to APM: {}", error);
+            let mut interval = time::interval_at(time::Instant::now() + period, period);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+            loop {
+                interval.tick().await;
+
+                if let Some(batch) = create_batch() {
+                    send(
+                        client.clone(),
+                        apm_address.clone(),
+                        authorization.clone(),
+                        batch,
+                    )
+                    .await;
+                }
             }
         });
     }
+
+    fn send(&self, batch: Batch) -> impl Future<Output = ()> {
+        send(
+            self.client.clone(),
+            self.apm_address.clone(),
+            self.authorization.clone(),
+            batch,
+        )
+    }
+}
+
+async fn send(
+    client: Client,
+    apm_address: Arc<String>,
+    authorization: Option<Arc<String>>,
+    batch: Batch,
+) {
+    let _subscriber_guard = subscriber::set_default(NoSubscriber::default());
+    let mut request = client
+        .post(format!("{}/intake/v2/events", apm_address))
+        .header(
+            header::CONTENT_TYPE,
+            header::HeaderValue::from_static("application/x-ndjson"),
+        )
+        .body(batch.to_string());
+
+    if let Some(authorization) = &authorization {
+        request = request.header(header::AUTHORIZATION, authorization.deref());
+    }
+
+    let result = request.send().await;
+    if let Err(error) = result {
+        eprintln!("Error sending batch to APM: {}", error);
+    }
 }
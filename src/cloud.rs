@@ -0,0 +1,370 @@
+use std::time::Duration;
+
+use reqwest::{Client, RequestBuilder};
+use serde_json::Value;
+use tokio::task::JoinSet;
+
+use crate::{
+    config::{CloudMetadataEndpoints, CloudProvider},
+    model::{Account, Cloud, Instance, Machine, Project},
+};
+
+/// Timeout of a single metadata request. Metadata services are local to the instance, so they
+/// respond quickly if present at all.
+pub(crate) const CLOUD_METADATA_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Creates a client for metadata requests. Metadata services are link-local, so neither proxies
+/// nor APM server TLS settings apply to them.
+pub(crate) fn metadata_client() -> Option<Client> {
+    Client::builder().no_proxy().build().ok()
+}
+
+/// Queries instance metadata services of given provider. With `CloudProvider::Auto`, all providers
+/// are queried concurrently and the first successful response wins.
+pub(crate) async fn detect(
+    client: Client,
+    provider: CloudProvider,
+    endpoints: CloudMetadataEndpoints,
+) -> Option<Cloud> {
+    match provider {
+        CloudProvider::Aws => aws(&client, &endpoints.aws).await,
+        CloudProvider::Gcp => gcp(&client, &endpoints.gcp).await,
+        CloudProvider::Azure => azure(&client, &endpoints.azure).await,
+        CloudProvider::Auto => {
+            let CloudMetadataEndpoints {
+                aws: aws_address,
+                gcp: gcp_address,
+                azure: azure_address,
+            } = endpoints;
+
+            let mut detections = JoinSet::new();
+            detections.spawn({
+                let client = client.clone();
+                async move { aws(&client, &aws_address).await }
+            });
+            detections.spawn({
+                let client = client.clone();
+                async move { gcp(&client, &gcp_address).await }
+            });
+            detections.spawn(async move { azure(&client, &azure_address).await });
+
+            // remaining detections are aborted when the set is dropped
+            while let Some(detection) = detections.join_next().await {
+                if let Ok(Some(cloud)) = detection {
+                    return Some(cloud);
+                }
+            }
+
+            None
+        }
+        CloudProvider::None => None,
+    }
+}
+
+async fn aws(client: &Client, address: &str) -> Option<Cloud> {
+    // IMDSv2 requires a session token, but IMDSv1 might still be available without one
+    let token = fetch(
+        client
+            .put(format!("{}/latest/api/token", address))
+            .header("X-aws-ec2-metadata-token-ttl-seconds", "300"),
+    )
+    .await;
+
+    let mut request = client.get(format!(
+        "{}/latest/dynamic/instance-identity/document",
+        address
+    ));
+    if let Some(token) = token {
+        request = request.header("X-aws-ec2-metadata-token", token);
+    }
+
+    let document = serde_json::from_str(&fetch(request).await?).ok()?;
+    parse_aws(&document)
+}
+
+async fn gcp(client: &Client, address: &str) -> Option<Cloud> {
+    let metadata = fetch(
+        client
+            .get(format!("{}/computeMetadata/v1/?recursive=true", address))
+            .header("Metadata-Flavor", "Google"),
+    )
+    .await?;
+
+    parse_gcp(&serde_json::from_str(&metadata).ok()?)
+}
+
+async fn azure(client: &Client, address: &str) -> Option<Cloud> {
+    let metadata = fetch(
+        client
+            .get(format!(
+                "{}/metadata/instance/compute?api-version=2019-08-15",
+                address
+            ))
+            .header("Metadata", "true"),
+    )
+    .await?;
+
+    parse_azure(&serde_json::from_str(&metadata).ok()?)
+}
+
+async fn fetch(request: RequestBuilder) -> Option<String> {
+    let response = request
+        .timeout(CLOUD_METADATA_TIMEOUT)
+        .send()
+        .await
+        .ok()?
+        .error_for_status()
+        .ok()?;
+
+    response.text().await.ok()
+}
+
+/// Parses AWS instance identity document.
+fn parse_aws(document: &Value) -> Option<Cloud> {
+    Some(Cloud {
+        provider: "aws".to_string(),
+        account: string(document, "accountId").map(|id| Account {
+            id: Some(id),
+            name: None,
+        }),
+        instance: Some(Instance {
+            id: Some(string(document, "instanceId")?),
+            name: None,
+        }),
+        machine: string(document, "instanceType").map(|machine_type| Machine {
+            machine_type: Some(machine_type),
+        }),
+        availability_zone: string(document, "availabilityZone"),
+        region: string(document, "region"),
+        ..Default::default()
+    })
+}
+
+/// Parses GCP recursive metadata. Zone and machine type are given as resource paths, e.g.
+/// `projects/123/zones/us-west1-a`.
+fn parse_gcp(metadata: &Value) -> Option<Cloud> {
+    let instance = metadata.get("instance")?;
+    let project = metadata.get("project");
+
+    let availability_zone = string(instance, "zone").map(|zone| last_segment(&zone));
+    let region = availability_zone
+        .as_deref()
+        .and_then(|zone| zone.rsplit_once('-'))
+        .map(|(region, _)| region.to_string());
+
+    Some(Cloud {
+        provider: "gcp".to_string(),
+        instance: Some(Instance {
+            id: Some(string(instance, "id")?),
+            name: string(instance, "name"),
+        }),
+        machine: string(instance, "machineType").map(|machine_type| Machine {
+            machine_type: Some(last_segment(&machine_type)),
+        }),
+        project: project.map(|project| Project {
+            id: string(project, "numericProjectId"),
+            name: string(project, "projectId"),
+        }),
+        availability_zone,
+        region,
+        ..Default::default()
+    })
+}
+
+/// Parses Azure compute instance metadata.
+fn parse_azure(metadata: &Value) -> Option<Cloud> {
+    Some(Cloud {
+        provider: "azure".to_string(),
+        account: string(metadata, "subscriptionId").map(|id| Account {
+            id: Some(id),
+            name: None,
+        }),
+        instance: Some(Instance {
+            id: Some(string(metadata, "vmId")?),
+            name: string(metadata, "name"),
+        }),
+        machine: string(metadata, "vmSize").map(|machine_type| Machine {
+            machine_type: Some(machine_type),
+        }),
+        project: string(metadata, "resourceGroupName").map(|name| Project {
+            id: None,
+            name: Some(name),
+        }),
+        availability_zone: string(metadata, "zone"),
+        region: string(metadata, "location"),
+    })
+}
+
+/// Returns a non-empty value as a string, including numeric values.
+fn string(value: &Value, name: &str) -> Option<String> {
+    match value.get(name)? {
+        Value::String(value) if !value.is_empty() => Some(value.clone()),
+        Value::Number(value) => Some(value.to_string()),
+        _ => None,
+    }
+}
+
+#[inline]
+fn last_segment(path: &str) -> String {
+    path.rsplit('/').next().unwrap_or(path).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader, Write},
+        net::TcpListener,
+        thread,
+    };
+
+    use serde_json::json;
+
+    use super::*;
+
+    /// Serves AWS metadata, requiring an IMDSv2 token.
+    fn aws_stand_in() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = format!("http://{}", listener.local_addr().unwrap());
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+
+                let mut token = None;
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap() > 2 {
+                    if let Some(value) = line
+                        .to_lowercase()
+                        .strip_prefix("x-aws-ec2-metadata-token:")
+                    {
+                        token = Some(value.trim().to_string());
+                    }
+                    line.clear();
+                }
+
+                let (status, body) = if request_line.starts_with("PUT /latest/api/token") {
+                    ("200 OK", "token".to_string())
+                } else if request_line.starts_with("GET /latest/dynamic/instance-identity/document")
+                    && token.as_deref() == Some("token")
+                {
+                    (
+                        "200 OK",
+                        json!({
+                            "accountId": "123456789012",
+                            "availabilityZone": "us-east-1a",
+                            "instanceId": "i-0123456789abcdef0",
+                            "instanceType": "t3.micro",
+                            "region": "us-east-1",
+                        })
+                        .to_string(),
+                    )
+                } else {
+                    ("404 Not Found", String::new())
+                };
+
+                write!(
+                    stream,
+                    "HTTP/1.1 {}\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                )
+                .unwrap();
+            }
+        });
+
+        address
+    }
+
+    #[test]
+    fn test_detect() {
+        let address = aws_stand_in();
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+
+        let endpoints =
+            || CloudMetadataEndpoints::new(address.clone(), address.clone(), address.clone());
+
+        let cloud = runtime
+            .block_on(detect(
+                metadata_client().unwrap(),
+                CloudProvider::Auto,
+                endpoints(),
+            ))
+            .unwrap();
+        assert_eq!(cloud.provider, "aws");
+        assert_eq!(
+            cloud.instance.unwrap().id.as_deref(),
+            Some("i-0123456789abcdef0")
+        );
+        assert_eq!(
+            cloud.machine.unwrap().machine_type.as_deref(),
+            Some("t3.micro")
+        );
+        assert_eq!(cloud.region.as_deref(), Some("us-east-1"));
+        assert_eq!(cloud.availability_zone.as_deref(), Some("us-east-1a"));
+        assert_eq!(cloud.account.unwrap().id.as_deref(), Some("123456789012"));
+
+        assert!(runtime
+            .block_on(detect(
+                metadata_client().unwrap(),
+                CloudProvider::Gcp,
+                endpoints()
+            ))
+            .is_none());
+    }
+
+    #[test]
+    fn test_parse() {
+        let gcp = parse_gcp(&json!({
+            "instance": {
+                "id": 4306570268266786072_u64,
+                "machineType": "projects/513326162531/machineTypes/n1-standard-1",
+                "name": "basepi-test",
+                "zone": "projects/513326162531/zones/us-west3-a",
+            },
+            "project": {"numericProjectId": 513326162531_u64, "projectId": "elastic-apm"},
+        }))
+        .unwrap();
+        assert_eq!(
+            gcp.instance.unwrap().id.as_deref(),
+            Some("4306570268266786072")
+        );
+        assert_eq!(
+            gcp.machine.unwrap().machine_type.as_deref(),
+            Some("n1-standard-1")
+        );
+        assert_eq!(gcp.availability_zone.as_deref(), Some("us-west3-a"));
+        assert_eq!(gcp.region.as_deref(), Some("us-west3"));
+        assert_eq!(gcp.project.unwrap().name.as_deref(), Some("elastic-apm"));
+
+        let azure = parse_azure(&json!({
+            "location": "westus2",
+            "name": "basepi-test",
+            "resourceGroupName": "basepi-testing",
+            "subscriptionId": "7657426d-c4c3-44ac-88a2-3b2cd59e6dba",
+            "vmId": "e11ebedc-019d-427f-84dd-56cd4388d3a8",
+            "vmSize": "Standard_D2s_v3",
+            "zone": "",
+        }))
+        .unwrap();
+        assert_eq!(azure.region.as_deref(), Some("westus2"));
+        assert_eq!(azure.availability_zone, None);
+        assert_eq!(
+            azure.machine.unwrap().machine_type.as_deref(),
+            Some("Standard_D2s_v3")
+        );
+        assert_eq!(
+            azure.project.unwrap().name.as_deref(),
+            Some("basepi-testing")
+        );
+
+        assert!(parse_aws(&json!({})).is_none());
+    }
+}
@@ -1,10 +1,96 @@
 //! Layer configuration.
 
+use std::{str::FromStr, time::Duration};
+
+use anyhow::anyhow;
+use fxhash::FxHashMap;
+use serde_json::Value;
+use tracing::{Level, Metadata};
+
 use crate::model::{Cloud, Framework, Language, Process, Runtime, ServiceNode, System, User};
 
 /// Name for the trace id field, if one needs to be supplied manually.
 pub const TRACE_ID_FIELD_NAME: &str = "trace_id";
 
+/// Name for the field holding a `valuable` `model::Request`, which populates the transaction
+/// request context.
+pub const HTTP_REQUEST_FIELD_NAME: &str = "http.request";
+
+/// Name for the field holding a `valuable` `model::Response`, which populates the transaction
+/// response context.
+pub const HTTP_RESPONSE_FIELD_NAME: &str = "http.response";
+
+/// Name for the field holding a `valuable` `model::Db`, which populates the span db context.
+pub const DB_FIELD_NAME: &str = "db";
+
+/// Name for the field holding a `valuable` `model::Http`, which populates the span http context.
+pub const HTTP_FIELD_NAME: &str = "http";
+
+/// Name for the field holding a `valuable` `model::Message`, which populates the transaction or
+/// span message context.
+pub const MESSAGE_FIELD_NAME: &str = "messaging";
+
+/// Name for the field holding the user id, which populates the transaction user context.
+pub const USER_ID_FIELD_NAME: &str = "user.id";
+
+/// Name for the field holding the user email, which populates the transaction user context.
+pub const USER_EMAIL_FIELD_NAME: &str = "user.email";
+
+/// Name for the field holding the user name, which populates the transaction user context.
+pub const USER_NAME_FIELD_NAME: &str = "user.name";
+
+/// Name for the field holding explicit transaction or span outcome: `success`, `failure` or
+/// `unknown`.
+pub const OUTCOME_FIELD_NAME: &str = "outcome";
+
+/// Name for the field, which marks the transaction or span as failed when recorded with a value
+/// other than `false`.
+pub const ERROR_FIELD_NAME: &str = "error";
+
+/// Name for the field holding the transaction result, e.g. `HTTP 2xx`. Can be declared empty and
+/// recorded later. Derived from the HTTP status code, if not recorded.
+pub const RESULT_FIELD_NAME: &str = "transaction.result";
+
+/// Name for the field holding the HTTP status code, used to derive outcome and result when no
+/// `valuable` response is recorded.
+pub const HTTP_STATUS_CODE_FIELD_NAME: &str = "http.status_code";
+
+/// Name for the event field holding a transaction mark, e.g. `db_ready` or `startup.db_ready`.
+/// Marks record the time elapsed since the start of the enclosing transaction.
+pub const MARK_FIELD_NAME: &str = "mark";
+
+/// Name for the event field holding the message template of an error, e.g. `"user {} logged in"`.
+/// The template cannot be recovered from formatted messages, so it's only sent if supplied
+/// explicitly. Not sent as a label.
+pub const PARAM_MESSAGE_FIELD_NAME: &str = "param_message";
+
+/// Prefix of event fields recorded as counters, e.g. `metric.counter.orders = 1`. Counters are
+/// summed within the metrics interval.
+pub const METRIC_COUNTER_FIELD_PREFIX: &str = "metric.counter.";
+
+/// Prefix of event fields recorded as gauges, e.g. `metric.gauge.queue_depth = 42`. Gauges report
+/// the last value recorded within the metrics interval.
+pub const METRIC_GAUGE_FIELD_PREFIX: &str = "metric.gauge.";
+
+/// Default interval of sending metrics.
+pub const DEFAULT_METRICS_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Default field name patterns, which values are redacted before sending to APM.
+pub const DEFAULT_SANITIZE_FIELD_NAMES: &[&str] = &[
+    "password",
+    "passwd",
+    "pwd",
+    "secret",
+    "*key",
+    "*token*",
+    "*session*",
+    "*credit*",
+    "*card*",
+    "*auth*",
+    "set-cookie",
+    "*principal*",
+];
+
 pub struct Service {
     pub(crate) version: Option<String>,
     pub(crate) environment: Option<String>,
@@ -45,6 +131,84 @@ impl ApiKey {
     }
 }
 
+/// Field name patterns deciding which fields are captured as labels. Patterns are
+/// case-insensitive and support `*` wildcards. Empty `include` allows all fields.
+#[derive(Default)]
+pub struct FieldFilter {
+    pub(crate) include: Vec<String>,
+    pub(crate) exclude: Vec<String>,
+}
+
+impl FieldFilter {
+    pub fn new(include: Vec<String>, exclude: Vec<String>) -> Self {
+        FieldFilter { include, exclude }
+    }
+}
+
+/// Fields recorded on an event, used when deciding if an event should be reported as an error.
+pub struct EventFields<'a>(pub(crate) &'a FxHashMap<String, Value>);
+
+impl EventFields<'_> {
+    /// Returns the value of the field with given name, if recorded.
+    pub fn get(&self, name: &str) -> Option<&Value> {
+        self.0.get(name)
+    }
+}
+
+/// Cloud provider whose instance metadata service is queried for cloud metadata.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum CloudProvider {
+    /// Tries all supported providers.
+    Auto,
+    Aws,
+    Gcp,
+    Azure,
+    /// Disables cloud metadata detection.
+    #[default]
+    None,
+}
+
+impl FromStr for CloudProvider {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_ascii_lowercase().as_str() {
+            "auto" => Ok(CloudProvider::Auto),
+            "aws" => Ok(CloudProvider::Aws),
+            "gcp" => Ok(CloudProvider::Gcp),
+            "azure" => Ok(CloudProvider::Azure),
+            "none" => Ok(CloudProvider::None),
+            _ => Err(anyhow!("Unknown cloud provider: {}", value)),
+        }
+    }
+}
+
+/// Base addresses of cloud instance metadata services.
+pub struct CloudMetadataEndpoints {
+    pub(crate) aws: String,
+    pub(crate) gcp: String,
+    pub(crate) azure: String,
+}
+
+impl CloudMetadataEndpoints {
+    pub fn new(aws: String, gcp: String, azure: String) -> Self {
+        CloudMetadataEndpoints { aws, gcp, azure }
+    }
+}
+
+impl Default for CloudMetadataEndpoints {
+    fn default() -> Self {
+        CloudMetadataEndpoints {
+            aws: "http://169.254.169.254".to_string(),
+            gcp: "http://metadata.google.internal".to_string(),
+            azure: "http://169.254.169.254".to_string(),
+        }
+    }
+}
+
+/// Predicate deciding if an event should be reported as an error.
+pub type ErrorFilter = dyn Fn(&Metadata<'_>, &EventFields<'_>) -> bool + Send + Sync;
+
 /// APM authorization method.
 pub enum Authorization {
     SecretToken(String),
@@ -62,6 +226,26 @@ pub struct Config {
     pub(crate) cloud: Option<Cloud>,
     pub(crate) allow_invalid_certs: bool,
     pub(crate) root_cert_path: Option<String>,
+    pub(crate) sanitize_field_names: Option<Vec<String>>,
+    pub(crate) field_filter: Option<FieldFilter>,
+    pub(crate) target_field_filters: Vec<(String, FieldFilter)>,
+    pub(crate) error_level: Option<Level>,
+    pub(crate) error_filter: Option<Box<ErrorFilter>>,
+    pub(crate) error_stacktraces: bool,
+    pub(crate) span_stacktrace_min_duration: Option<Duration>,
+    pub(crate) panic_hook: bool,
+    pub(crate) error_deduplication_window: Option<Duration>,
+    pub(crate) log_level: Option<Level>,
+    pub(crate) metrics_interval: Option<Duration>,
+    pub(crate) breakdown_metrics: bool,
+    pub(crate) system_metrics: bool,
+    pub(crate) custom_metrics: bool,
+    pub(crate) transaction_metrics: bool,
+    pub(crate) suppress_argv: bool,
+    pub(crate) cloud_provider: CloudProvider,
+    pub(crate) cloud_metadata_endpoints: Option<CloudMetadataEndpoints>,
+    #[cfg(feature = "tokio-metrics")]
+    pub(crate) tokio_runtime: Option<tokio::runtime::Handle>,
 }
 
 impl Config {
@@ -92,11 +276,20 @@ impl Config {
         self
     }
 
+    /// Sets process metadata. Parent process ID, title and arguments are detected when not set.
     pub fn with_process(mut self, process: Process) -> Self {
         self.process = Some(process);
         self
     }
 
+    /// Prevents sending process command line arguments, which might contain secrets.
+    pub fn with_suppress_argv(mut self, suppress_argv: bool) -> Self {
+        self.suppress_argv = suppress_argv;
+        self
+    }
+
+    /// Sets system metadata. Hostname, architecture, platform, container and Kubernetes metadata
+    /// are detected when not set.
     pub fn with_system(mut self, system: System) -> Self {
         self.system = Some(system);
         self
@@ -111,4 +304,133 @@ impl Config {
         self.cloud = Some(cloud);
         self
     }
+
+    /// Detects cloud metadata by querying the instance metadata service of given provider during
+    /// layer creation. Ignored if cloud metadata is set explicitly.
+    pub fn with_cloud_provider(mut self, cloud_provider: CloudProvider) -> Self {
+        self.cloud_provider = cloud_provider;
+        self
+    }
+
+    /// Overrides addresses of cloud instance metadata services.
+    pub fn with_cloud_metadata_endpoints(mut self, endpoints: CloudMetadataEndpoints) -> Self {
+        self.cloud_metadata_endpoints = Some(endpoints);
+        self
+    }
+
+    /// Sets field name patterns, which values should be redacted in labels and headers. Patterns
+    /// are case-insensitive and support `*` wildcards. Defaults to `DEFAULT_SANITIZE_FIELD_NAMES`.
+    pub fn with_sanitize_field_names(mut self, sanitize_field_names: Vec<String>) -> Self {
+        self.sanitize_field_names = Some(sanitize_field_names);
+        self
+    }
+
+    /// Sets a filter for fields captured as labels for all targets.
+    pub fn with_field_filter(mut self, filter: FieldFilter) -> Self {
+        self.field_filter = Some(filter);
+        self
+    }
+
+    /// Adds a filter for fields captured as labels for given target and its children, e.g.
+    /// `my_crate::db` applies to `my_crate::db` and `my_crate::db::pool`. Applied in addition to
+    /// the global filter.
+    pub fn with_target_field_filter(mut self, target: String, filter: FieldFilter) -> Self {
+        self.target_field_filters.push((target, filter));
+        self
+    }
+
+    /// Sets the least severe level of events reported as errors. Defaults to `Level::ERROR`.
+    pub fn with_error_level(mut self, level: Level) -> Self {
+        self.error_level = Some(level);
+        self
+    }
+
+    /// Sets an additional predicate for events passing the error level threshold. Only events for
+    /// which the predicate returns `true` are reported as errors.
+    pub fn with_error_filter<F>(mut self, filter: F) -> Self
+    where
+        F: Fn(&Metadata<'_>, &EventFields<'_>) -> bool + Send + Sync + 'static,
+    {
+        self.error_filter = Some(Box::new(filter));
+        self
+    }
+
+    /// Enables capturing stack traces for reported errors. Disabled by default, since capturing
+    /// and symbolizing a stack trace is expensive.
+    pub fn with_error_stacktraces(mut self, error_stacktraces: bool) -> Self {
+        self.error_stacktraces = error_stacktraces;
+        self
+    }
+
+    /// Enables capturing stack traces, when closing spans which took at least given duration.
+    pub fn with_span_stacktrace_min_duration(mut self, min_duration: Duration) -> Self {
+        self.span_stacktrace_min_duration = Some(min_duration);
+        self
+    }
+
+    /// Installs a panic hook reporting panics as unhandled errors, attached to the current span.
+    /// Reporting blocks the panicking thread until the error is sent, or a timeout elapses. The
+    /// hook is installed once, when the layer is added to a subscriber.
+    pub fn with_panic_hook(mut self, panic_hook: bool) -> Self {
+        self.panic_hook = panic_hook;
+        self
+    }
+
+    /// Enables reporting at most one error per grouping key (target, event callsite and exception
+    /// type) in given time window, so errors with interpolated values are grouped together. The
+    /// number of suppressed errors is reported as the `suppressed_errors` label on the next
+    /// reported occurrence.
+    pub fn with_error_deduplication_window(mut self, window: Duration) -> Self {
+        self.error_deduplication_window = Some(window);
+        self
+    }
+
+    /// Enables sending events at given level or more severe as log records, correlated with the
+    /// current trace. Requires APM Server 8.0 or newer.
+    pub fn with_log_level(mut self, level: Level) -> Self {
+        self.log_level = Some(level);
+        self
+    }
+
+    /// Sets the interval of sending metrics. Defaults to `DEFAULT_METRICS_INTERVAL`.
+    pub fn with_metrics_interval(mut self, interval: Duration) -> Self {
+        self.metrics_interval = Some(interval);
+        self
+    }
+
+    /// Enables breakdown metrics, i.e. span self time per transaction and span type.
+    pub fn with_breakdown_metrics(mut self, breakdown_metrics: bool) -> Self {
+        self.breakdown_metrics = breakdown_metrics;
+        self
+    }
+
+    /// Enables process and system CPU and memory metrics. Only supported on Linux, ignored
+    /// elsewhere.
+    pub fn with_system_metrics(mut self, system_metrics: bool) -> Self {
+        self.system_metrics = system_metrics;
+        self
+    }
+
+    /// Enables custom metrics recorded through events with fields prefixed by
+    /// `METRIC_COUNTER_FIELD_PREFIX` or `METRIC_GAUGE_FIELD_PREFIX`. Other event fields are used as
+    /// metric tags. Such events are not reported in any other way.
+    pub fn with_custom_metrics(mut self, custom_metrics: bool) -> Self {
+        self.custom_metrics = custom_metrics;
+        self
+    }
+
+    /// Enables transaction duration histogram and summary metrics, aggregated per transaction
+    /// name, type and result.
+    pub fn with_transaction_metrics(mut self, transaction_metrics: bool) -> Self {
+        self.transaction_metrics = transaction_metrics;
+        self
+    }
+
+    /// Enables metrics of the runtime behind given handle, e.g. worker busy ratio, queue depths and
+    /// task counts.
+    #[cfg(feature = "tokio-metrics")]
+    pub fn with_tokio_runtime_metrics(mut self, handle: tokio::runtime::Handle) -> Self {
+        self.tokio_runtime = Some(handle);
+        self
+    }
 }
@@ -0,0 +1,322 @@
+use std::{env, str::SplitWhitespace};
+
+use crate::model::{Container, Kubernetes, Node, Pod, Process, System};
+
+/// Fills in process metadata which has not been configured explicitly.
+pub(crate) fn process(configured: Option<Process>, suppress_argv: bool) -> Process {
+    let mut process = configured.unwrap_or_else(|| Process {
+        pid: std::process::id() as i32,
+        ..Default::default()
+    });
+
+    #[cfg(target_os = "linux")]
+    if process.ppid.is_none() || process.title.is_none() {
+        if let Some((ppid, title)) = std::fs::read_to_string("/proc/self/stat")
+            .ok()
+            .and_then(|stat| parse_process_stat(&stat))
+        {
+            process.ppid.get_or_insert(ppid);
+            process.title.get_or_insert(title);
+        }
+    }
+
+    if process.title.is_none() {
+        process.title = env::current_exe().ok().and_then(|path| {
+            path.file_stem()
+                .map(|name| name.to_string_lossy().into_owned())
+        });
+    }
+
+    if suppress_argv {
+        process.argv = None;
+    } else if process.argv.is_none() {
+        process.argv = Some(env::args().collect());
+    }
+
+    process
+}
+
+/// Fills in system metadata which has not been configured explicitly.
+pub(crate) fn system(configured: Option<System>) -> System {
+    let mut system = configured.unwrap_or_default();
+
+    if system.detected_hostname.is_none() {
+        system.detected_hostname = hostname();
+    }
+    if system.architecture.is_none() {
+        system.architecture = Some(env::consts::ARCH.to_string());
+    }
+    if system.platform.is_none() {
+        system.platform = Some(env::consts::OS.to_string());
+    }
+
+    #[cfg(target_os = "linux")]
+    let (container_id, pod_uid) = container();
+    #[cfg(not(target_os = "linux"))]
+    let (container_id, pod_uid) = (None, None);
+
+    if system.container.is_none() {
+        system.container = container_id.map(|id| Container { id });
+    }
+    if system.kubernetes.is_none() {
+        system.kubernetes = kubernetes(pod_uid, system.detected_hostname.as_deref());
+    }
+
+    system
+}
+
+/// Detects container ID and Kubernetes pod UID, looking at cgroup v1 paths first and falling back
+/// to cgroup v2 mounts.
+#[cfg(target_os = "linux")]
+fn container() -> (Option<String>, Option<String>) {
+    let (container_id, pod_uid) = std::fs::read_to_string("/proc/self/cgroup")
+        .map(|cgroup| parse_cgroup(&cgroup))
+        .unwrap_or_default();
+
+    let container_id = container_id.or_else(|| {
+        std::fs::read_to_string("/proc/self/mountinfo")
+            .ok()
+            .and_then(|mountinfo| parse_mountinfo(&mountinfo))
+    });
+
+    (container_id, pod_uid)
+}
+
+/// Reads Kubernetes metadata from the downward API environment variables. Pod UID detected from
+/// cgroups is used if the environment doesn't provide one, in which case pod name defaults to the
+/// hostname.
+fn kubernetes(pod_uid: Option<String>, hostname: Option<&str>) -> Option<Kubernetes> {
+    let var = |name| {
+        env::var(name)
+            .ok()
+            .filter(|value: &String| !value.is_empty())
+    };
+
+    let namespace = var("KUBERNETES_NAMESPACE");
+    let node_name = var("KUBERNETES_NODE_NAME");
+    let mut pod_name = var("KUBERNETES_POD_NAME");
+    let env_pod_uid = var("KUBERNETES_POD_UID");
+
+    if env_pod_uid.is_none() && pod_uid.is_some() && pod_name.is_none() {
+        pod_name = hostname.map(String::from);
+    }
+    let pod_uid = env_pod_uid.or(pod_uid);
+
+    if namespace.is_none() && node_name.is_none() && pod_name.is_none() && pod_uid.is_none() {
+        return None;
+    }
+
+    Some(Kubernetes {
+        namespace,
+        pod: if pod_name.is_some() || pod_uid.is_some() {
+            Some(Pod {
+                name: pod_name,
+                uid: pod_uid,
+            })
+        } else {
+            None
+        },
+        node: node_name.map(|name| Node { name: Some(name) }),
+    })
+}
+
+fn hostname() -> Option<String> {
+    #[cfg(target_os = "linux")]
+    if let Ok(hostname) = std::fs::read_to_string("/proc/sys/kernel/hostname") {
+        let hostname = hostname.trim();
+        if !hostname.is_empty() {
+            return Some(hostname.to_string());
+        }
+    }
+
+    env::var("HOSTNAME")
+        .or_else(|_| env::var("COMPUTERNAME"))
+        .ok()
+        .map(|hostname| hostname.trim().to_string())
+        .filter(|hostname| !hostname.is_empty())
+}
+
+/// Splits `/proc/<pid>/stat` into the process name and the remaining fields, starting with the
+/// process state.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+pub(crate) fn split_process_stat(stat: &str) -> Option<(&str, SplitWhitespace<'_>)> {
+    // process name can contain spaces and parentheses, so look for the last one
+    let (head, fields) = stat.rsplit_once(')')?;
+    let (_, name) = head.split_once('(')?;
+
+    Some((name, fields.split_whitespace()))
+}
+
+/// Parses parent process ID and process name from `/proc/self/stat`.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn parse_process_stat(stat: &str) -> Option<(i32, String)> {
+    let (name, mut fields) = split_process_stat(stat)?;
+    let ppid = fields.nth(1)?.parse().ok()?;
+
+    Some((ppid, name.to_string()))
+}
+
+/// Parses container ID and Kubernetes pod UID from cgroup v1 `/proc/self/cgroup` entries, e.g.
+/// `12:pids:/kubepods/besteffort/pod<uid>/<id>` or
+/// `1:name=systemd:/system.slice/docker-<id>.scope`.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn parse_cgroup(cgroup: &str) -> (Option<String>, Option<String>) {
+    for line in cgroup.lines() {
+        let path = match line.splitn(3, ':').nth(2) {
+            Some(path) => path,
+            None => continue,
+        };
+        let (dirname, basename) = match path.rsplit_once('/') {
+            Some(parts) => parts,
+            None => continue,
+        };
+
+        let container_id = match basename.strip_suffix(".scope") {
+            Some(scope) => scope.rsplit_once('-').map(|(_, id)| id).unwrap_or(scope),
+            None => basename,
+        };
+        if !is_container_id(container_id) {
+            continue;
+        }
+
+        let pod_uid = if dirname.contains("kubepods") {
+            let pod = dirname.rsplit('/').next().unwrap_or_default();
+            match pod.strip_suffix(".slice") {
+                // systemd driver, e.g. kubepods-besteffort-pod<uid>.slice
+                Some(slice) => slice
+                    .rsplit_once("-pod")
+                    .map(|(_, uid)| uid.replace('_', "-")),
+                None => pod.strip_prefix("pod").map(String::from),
+            }
+        } else {
+            None
+        };
+
+        return (Some(container_id.to_string()), pod_uid);
+    }
+
+    (None, None)
+}
+
+/// Parses container ID from cgroup v2 `/proc/self/mountinfo`, where the container hostname file is
+/// mounted from the container runtime directory, e.g. `/var/lib/docker/containers/<id>/hostname`.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn parse_mountinfo(mountinfo: &str) -> Option<String> {
+    mountinfo.lines().find_map(|line| {
+        let root = line.split_whitespace().nth(3)?;
+        let directory = root.strip_suffix("/hostname")?;
+
+        directory
+            .rsplit('/')
+            .find(|segment| is_container_id(segment))
+            .map(String::from)
+    })
+}
+
+/// Checks if the value looks like a container ID: 64 hex characters, a UUID or an ECS task ID.
+fn is_container_id(value: &str) -> bool {
+    fn is_hex(value: &str, len: usize) -> bool {
+        value.len() == len && value.chars().all(|c| c.is_ascii_hexdigit())
+    }
+
+    if is_hex(value, 64) {
+        return true;
+    }
+
+    let parts = value.split('-').collect::<Vec<_>>();
+    match parts.as_slice() {
+        [a, b, c, d, e] => {
+            is_hex(a, 8) && is_hex(b, 4) && is_hex(c, 4) && is_hex(d, 4) && is_hex(e, 12)
+        }
+        [task, suffix] => {
+            is_hex(task, 32) && suffix.len() == 10 && suffix.chars().all(|c| c.is_ascii_digit())
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_process_stat() {
+        assert_eq!(
+            parse_process_stat("1234 (my (app) name) S 1 1234 1234 0 -1 4194560 500 0 0 0"),
+            Some((1, "my (app) name".to_string()))
+        );
+        assert_eq!(parse_process_stat("garbage"), None);
+    }
+
+    const CONTAINER_ID: &str = "2227daf62df6694645fee5df53c1f91271546a9560e8600a525690ae252b7f63";
+
+    const CGROUP_V1_DOCKER: &str =
+        "12:devices:/docker/2227daf62df6694645fee5df53c1f91271546a9560e8600a525690ae252b7f63
+11:cpu,cpuacct:/docker/2227daf62df6694645fee5df53c1f91271546a9560e8600a525690ae252b7f63
+";
+
+    const CGROUP_V1_SYSTEMD: &str = "1:name=systemd:/system.slice/docker-2227daf62df6694645fee5df53c1f91271546a9560e8600a525690ae252b7f63.scope
+";
+
+    const CGROUP_V1_KUBERNETES: &str = "12:pids:/kubepods/besteffort/pod0e886e9a-3879-45f9-b44d-86ef9df03224/2227daf62df6694645fee5df53c1f91271546a9560e8600a525690ae252b7f63
+";
+
+    const CGROUP_V1_KUBERNETES_SYSTEMD: &str = "1:name=systemd:/kubepods.slice/kubepods-burstable.slice/kubepods-burstable-pod90d81341_92de_11e7_8cf2_507b9d4141fa.slice/crio-2227daf62df6694645fee5df53c1f91271546a9560e8600a525690ae252b7f63.scope
+";
+
+    const CGROUP_V1_ECS: &str = "3:cpuacct:/ecs/eb9d3d0c-8936-42d7-80d8-f82b2f1a629e/7e9139716d9e5d762d22f9f877b87d1be8b1449ac912c025a984750c5dbff157
+";
+
+    const CGROUP_V2: &str = "0::/
+";
+
+    const MOUNTINFO_V2: &str = "575 574 0:52 / / rw,relatime master:201 - overlay overlay rw
+609 575 259:1 /var/lib/docker/containers/2227daf62df6694645fee5df53c1f91271546a9560e8600a525690ae252b7f63/resolv.conf /etc/resolv.conf rw,relatime - ext4 /dev/nvme0n1p1 rw
+610 575 259:1 /var/lib/docker/containers/2227daf62df6694645fee5df53c1f91271546a9560e8600a525690ae252b7f63/hostname /etc/hostname rw,relatime - ext4 /dev/nvme0n1p1 rw
+";
+
+    #[test]
+    fn test_parse_cgroup() {
+        assert_eq!(
+            parse_cgroup(CGROUP_V1_DOCKER),
+            (Some(CONTAINER_ID.to_string()), None)
+        );
+        assert_eq!(
+            parse_cgroup(CGROUP_V1_SYSTEMD),
+            (Some(CONTAINER_ID.to_string()), None)
+        );
+        assert_eq!(
+            parse_cgroup(CGROUP_V1_KUBERNETES),
+            (
+                Some(CONTAINER_ID.to_string()),
+                Some("0e886e9a-3879-45f9-b44d-86ef9df03224".to_string())
+            )
+        );
+        assert_eq!(
+            parse_cgroup(CGROUP_V1_KUBERNETES_SYSTEMD),
+            (
+                Some(CONTAINER_ID.to_string()),
+                Some("90d81341-92de-11e7-8cf2-507b9d4141fa".to_string())
+            )
+        );
+        assert_eq!(
+            parse_cgroup(CGROUP_V1_ECS),
+            (
+                Some(
+                    "7e9139716d9e5d762d22f9f877b87d1be8b1449ac912c025a984750c5dbff157".to_string()
+                ),
+                None
+            )
+        );
+        assert_eq!(parse_cgroup(CGROUP_V2), (None, None));
+    }
+
+    #[test]
+    fn test_parse_mountinfo() {
+        assert_eq!(
+            parse_mountinfo(MOUNTINFO_V2),
+            Some(CONTAINER_ID.to_string())
+        );
+        assert_eq!(parse_mountinfo(""), None);
+    }
+}
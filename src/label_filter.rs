@@ -0,0 +1,92 @@
+use crate::{
+    config::FieldFilter,
+    wildcard::{matches_any, WildcardMatcher},
+};
+
+struct Rules {
+    include: Vec<WildcardMatcher>,
+    exclude: Vec<WildcardMatcher>,
+}
+
+impl Rules {
+    fn new(filter: &FieldFilter) -> Self {
+        Rules {
+            include: filter
+                .include
+                .iter()
+                .map(|pattern| WildcardMatcher::new(pattern))
+                .collect(),
+            exclude: filter
+                .exclude
+                .iter()
+                .map(|pattern| WildcardMatcher::new(pattern))
+                .collect(),
+        }
+    }
+
+    fn allows(&self, field: &str) -> bool {
+        (self.include.is_empty() || matches_any(&self.include, field))
+            && !matches_any(&self.exclude, field)
+    }
+}
+
+/// Decides which fields are captured as labels, globally and per target.
+pub(crate) struct LabelFilter {
+    global: Option<Rules>,
+    targets: Vec<(String, Rules)>,
+}
+
+impl LabelFilter {
+    pub fn new(global: Option<FieldFilter>, targets: Vec<(String, FieldFilter)>) -> Self {
+        LabelFilter {
+            global: global.as_ref().map(Rules::new),
+            targets: targets
+                .iter()
+                .map(|(target, filter)| (target.clone(), Rules::new(filter)))
+                .collect(),
+        }
+    }
+
+    pub fn allows(&self, target: &str, field: &str) -> bool {
+        if let Some(global) = &self.global {
+            if !global.allows(field) {
+                return false;
+            }
+        }
+
+        self.targets
+            .iter()
+            .filter(|(prefix, _)| is_target_match(prefix, target))
+            .all(|(_, rules)| rules.allows(field))
+    }
+}
+
+#[inline]
+fn is_target_match(prefix: &str, target: &str) -> bool {
+    target
+        .strip_prefix(prefix)
+        .map(|rest| rest.is_empty() || rest.starts_with("::"))
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allows() {
+        let filter = LabelFilter::new(
+            Some(FieldFilter::new(vec![], vec!["*_id".to_string()])),
+            vec![(
+                "app::db".to_string(),
+                FieldFilter::new(vec!["db.*".to_string()], vec![]),
+            )],
+        );
+
+        assert!(filter.allows("app", "method"));
+        assert!(!filter.allows("app", "request_id"));
+        assert!(filter.allows("app::db::pool", "db.statement"));
+        assert!(!filter.allows("app::db", "method"));
+        assert!(filter.allows("app::dbx", "method"));
+    }
+}
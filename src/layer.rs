@@ -1,6 +1,11 @@
-use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::{
+    panic,
+    sync::{Arc, Mutex, Once, PoisonError},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
 use anyhow::Result as AnyResult;
+use fxhash::FxHashMap;
 use rand::random;
 use serde_json::{json, Value};
 use tracing::{
@@ -9,33 +14,129 @@ use tracing::{
 };
 use tracing_subscriber::{layer::Context, registry::LookupSpan, Layer};
 
+#[cfg(feature = "tokio-metrics")]
+use crate::runtime_metrics::RuntimeMetrics;
+#[cfg(target_os = "linux")]
+use crate::system_metrics::SystemMetrics;
 use crate::{
     apm_client::{ApmClient, Batch},
-    config::Config,
-    model::{Agent, Error, Log, Metadata, Service, Span, Transaction},
+    cloud::{self, CLOUD_METADATA_TIMEOUT},
+    config::{
+        CloudProvider, Config, ErrorFilter, EventFields, DEFAULT_METRICS_INTERVAL,
+        DEFAULT_SANITIZE_FIELD_NAMES, ERROR_FIELD_NAME, MARK_FIELD_NAME, OUTCOME_FIELD_NAME,
+        PARAM_MESSAGE_FIELD_NAME, RESULT_FIELD_NAME,
+    },
+    detect,
+    label_filter::LabelFilter,
+    metrics::{BreakdownMetrics, CustomMetrics, MetricSource, TransactionMetrics},
+    model::{
+        self, Agent, Error, Exception, Headers, Log, LogRecord, Metadata, Outcome, Service, Span,
+        Tags, Transaction, TransactionContext, User,
+    },
+    stacktrace,
     visitor::{ApmVisitor, TraceIdVisitor},
+    wildcard::{matches_any, WildcardMatcher},
 };
 
+const REDACTED: &str = "[REDACTED]";
+
+/// Target of events emitted by the panic hook.
+const PANIC_TARGET: &str = "tracing_elastic_apm::panic";
+const PANIC_LOCATION_FIELD_NAME: &str = "location";
+const PANIC_FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Label holding the number of identical errors suppressed since the last reported one.
+const SUPPRESSED_ERRORS_LABEL: &str = "suppressed_errors";
+
+/// Maximum number of tracked error groups. Once reached, the older half of groups is evicted.
+const MAX_ERROR_GROUPS: usize = 1000;
+
 #[derive(Copy, Clone)]
 struct TraceContext {
     pub trace_id: u128,
 }
 
+/// Trace context of the span enclosing an event.
+struct EventTrace {
+    trace_id: u128,
+    transaction_id: u64,
+    parent_id: u64,
+    user: Option<User>,
+}
+
+impl EventTrace {
+    fn new<S>(parent_id: &Id, ctx: &Context<'_, S>) -> Self
+    where
+        S: Subscriber + for<'lookup> LookupSpan<'lookup>,
+    {
+        let span = ctx.span(parent_id).expect("Span not found!");
+        let trace_ctx = *span
+            .extensions()
+            .get::<TraceContext>()
+            .expect("Trace context not found!");
+
+        // user context is taken from the enclosing transaction
+        let root = span
+            .scope()
+            .from_root()
+            .next()
+            .expect("Root span not found!");
+        let user = root
+            .extensions()
+            .get::<ApmVisitor>()
+            .and_then(|visitor| visitor.user());
+
+        EventTrace {
+            trace_id: trace_ctx.trace_id,
+            transaction_id: root.id().into_u64(),
+            parent_id: parent_id.into_u64(),
+            user,
+        }
+    }
+}
+
+struct ErrorGroup {
+    window_start: Instant,
+    suppressed: u64,
+}
+
 struct SpanContext {
     pub duration: Duration,
     pub last_timestamp: Instant,
+    pub failed: bool,
+    pub child_duration: Duration,
 }
 
 /// Telemetry capability that publishes events and spans to Elastic APM.
 pub struct ApmLayer {
     client: ApmClient,
     metadata: Value,
+    sanitize_field_names: Vec<WildcardMatcher>,
+    label_filter: LabelFilter,
+    error_level: Level,
+    error_filter: Option<Box<ErrorFilter>>,
+    error_stacktraces: bool,
+    panic_hook: bool,
+    span_stacktrace_min_duration: Option<Duration>,
+    error_deduplication_window: Option<Duration>,
+    error_groups: Mutex<FxHashMap<u64, ErrorGroup>>,
+    log_level: Option<Level>,
+    breakdown_metrics: Option<Arc<BreakdownMetrics>>,
+    custom_metrics: Option<Arc<CustomMetrics>>,
+    transaction_metrics: Option<Arc<TransactionMetrics>>,
 }
 
 impl<S> Layer<S> for ApmLayer
 where
     S: Subscriber + for<'lookup> LookupSpan<'lookup>,
 {
+    fn on_layer(&mut self, _subscriber: &mut S) {
+        // only install the hook once the layer is actually in use
+        if self.panic_hook {
+            install_panic_hook();
+        }
+    }
+
     fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
         let now = SystemTime::now()
             .duration_since(UNIX_EPOCH)
@@ -53,6 +154,8 @@ where
         extensions.insert(SpanContext {
             duration: Duration::new(0, 0),
             last_timestamp: timestamp,
+            failed: false,
+            child_duration: Duration::new(0, 0),
         });
 
         let name = span.name().to_string();
@@ -110,8 +213,31 @@ where
 
     fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
         let metadata = event.metadata();
-        if metadata.level() != &Level::ERROR {
-            return;
+
+        if let Some(custom_metrics) = &self.custom_metrics {
+            if metadata
+                .fields()
+                .iter()
+                .any(|field| CustomMetrics::is_metric_field(field.name()))
+            {
+                let mut visitor = ApmVisitor::default();
+                event.record(&mut visitor);
+
+                // only tags are sanitized and filtered, metric names are chosen by the user
+                let (metric_fields, tags) = visitor
+                    .labels
+                    .drain()
+                    .partition(|(name, _)| CustomMetrics::is_metric_field(name));
+                visitor.labels = tags;
+
+                sanitize(&mut visitor, &self.sanitize_field_names);
+                visitor
+                    .labels
+                    .retain(|name, _| self.label_filter.allows(metadata.target(), name));
+
+                custom_metrics.record(metric_fields, visitor.labels);
+                return;
+            }
         }
 
         let parent_id = if let Some(parent_id) = event.parent() {
@@ -124,36 +250,66 @@ where
             ctx.current_span().id().cloned()
         };
 
-        if let Some(parent_id) = &parent_id {
-            let span = ctx.span(parent_id).expect("Span not found!");
-            let extensions = span.extensions();
-            let trace_ctx = extensions
-                .get::<TraceContext>()
-                .expect("Trace context not found!");
+        // panics are always reported, regardless of error level and filter
+        let is_panic = metadata.target() == PANIC_TARGET;
 
-            let mut visitor = ApmVisitor::default();
-            event.record(&mut visitor);
-
-            let error = Error {
-                id: random::<u128>().to_string(),
-                trace_id: Some(trace_ctx.trace_id.to_string()),
-                parent_id: Some(parent_id.into_u64().to_string()),
-                culprit: Some(metadata.target().to_string()),
-                log: Some(Log {
-                    level: Some(metadata.level().to_string()),
-                    message: visitor
-                        .0
-                        .get("message")
-                        .map(|message| message.to_string())
-                        .unwrap_or_default(),
-                    ..Default::default()
-                }),
-                ..Default::default()
-            };
+        if is_panic || metadata.level() == &Level::ERROR {
+            if let Some(parent_id) = &parent_id {
+                let span = ctx.span(parent_id).expect("Span not found!");
+                let mut extensions = span.extensions_mut();
+                if let Some(span_ctx) = extensions.get_mut::<SpanContext>() {
+                    span_ctx.failed = true;
+                }
+            }
+        }
 
-            let metadata = self.create_metadata(&visitor, metadata);
-            let batch = Batch::new(metadata, None, None, Some(json!(error)));
-            self.client.send_batch(batch);
+        let report_error = is_panic || metadata.level() <= &self.error_level;
+        let report_log = self
+            .log_level
+            .map(|log_level| metadata.level() <= &log_level)
+            .unwrap_or(false);
+
+        let has_mark = metadata.fields().field(MARK_FIELD_NAME).is_some();
+
+        if !report_error && !report_log && !has_mark {
+            return;
+        }
+
+        let mut visitor = ApmVisitor::default();
+        event.record(&mut visitor);
+
+        if has_mark {
+            if let (Some(parent_id), Some(mark)) =
+                (&parent_id, visitor.label_string(MARK_FIELD_NAME))
+            {
+                record_mark(&mark, parent_id, &ctx);
+            }
+        }
+
+        let report_error = report_error
+            && (is_panic
+                || self
+                    .error_filter
+                    .as_ref()
+                    .map(|error_filter| error_filter(metadata, &EventFields(&visitor.labels)))
+                    .unwrap_or(true));
+
+        sanitize(&mut visitor, &self.sanitize_field_names);
+
+        // message template is reserved and never ends up as a label
+        let param_message = visitor.take_label_string(PARAM_MESSAGE_FIELD_NAME);
+
+        // events outside of any span are reported without trace context
+        let event_trace = parent_id
+            .as_ref()
+            .map(|parent_id| EventTrace::new(parent_id, &ctx));
+
+        if report_log {
+            self.report_log(&visitor, metadata, event_trace.as_ref());
+        }
+
+        if report_error {
+            self.report_error(visitor, param_message, metadata, event_trace, is_panic);
         }
     }
 
@@ -183,33 +339,138 @@ where
     fn on_close(&self, id: Id, ctx: Context<'_, S>) {
         let span = ctx.span(&id).expect("Span not found!");
         let mut extensions = span.extensions_mut();
-        let visitor = extensions
+        let mut visitor = extensions
             .remove::<ApmVisitor>()
             .expect("Visitor not found!");
         let span_ctx = extensions
             .remove::<SpanContext>()
             .expect("Span context not found!");
 
+        sanitize(&mut visitor, &self.sanitize_field_names);
+
+        // user fields are reserved and never end up as labels
+        let user = visitor.take_user();
+
+        // control fields are reserved as well, but need to be evaluated first
+        let failure_status_code = if span.parent().is_some() { 400 } else { 500 };
+        let outcome = outcome(&visitor, span_ctx.failed, failure_status_code);
+        let result = visitor.take_label_string(RESULT_FIELD_NAME);
+        visitor.labels.remove(OUTCOME_FIELD_NAME);
+        visitor.labels.remove(ERROR_FIELD_NAME);
+
         let metadata = self.create_metadata(&visitor, span.metadata());
         let duration = span_ctx.duration.as_micros() as f32 / 1000.;
+        let self_time = span_ctx.duration.saturating_sub(span_ctx.child_duration);
+
+        // breakdown is aggregated per enclosing transaction
+        let breakdown_transaction = match (&self.breakdown_metrics, span.parent()) {
+            (Some(_), Some(_)) => span.scope().from_root().next().and_then(|root| {
+                root.extensions().get::<Transaction>().map(|transaction| {
+                    (
+                        transaction.name.clone().unwrap_or_default(),
+                        transaction.transaction_type.clone(),
+                    )
+                })
+            }),
+            _ => None,
+        };
 
         let batch = if let Some(mut span) = extensions.remove::<Span>() {
             span.duration = duration;
+
+            if let (Some(breakdown_metrics), Some((transaction_name, transaction_type))) =
+                (&self.breakdown_metrics, &breakdown_transaction)
+            {
+                breakdown_metrics.record(
+                    transaction_name,
+                    transaction_type,
+                    &span.span_type,
+                    span.subtype.as_deref(),
+                    self_time,
+                );
+            }
+            span.outcome = Some(outcome);
+
+            if let Some(min_duration) = self.span_stacktrace_min_duration {
+                if span_ctx.duration >= min_duration {
+                    span.stacktrace = Some(stacktrace::capture());
+                }
+            }
+
+            if visitor.db.is_some() || visitor.http.is_some() || visitor.message.is_some() {
+                span.context = Some(model::SpanContext {
+                    db: visitor.db.take(),
+                    http: visitor.http.take(),
+                    message: visitor.message.take(),
+                    ..Default::default()
+                });
+            }
+
             Batch::new(metadata, None, Some(json!(span)), None)
         } else if let Some(mut transaction) = extensions.remove::<Transaction>() {
             transaction.duration = duration;
+
+            if let Some(breakdown_metrics) = &self.breakdown_metrics {
+                breakdown_metrics.record(
+                    transaction.name.as_deref().unwrap_or_default(),
+                    &transaction.transaction_type,
+                    "app",
+                    None,
+                    self_time,
+                );
+            }
+            transaction.outcome = Some(outcome);
+            transaction.result = result.or_else(|| {
+                visitor
+                    .status_code()
+                    .map(|status_code| format!("HTTP {}xx", status_code / 100))
+            });
+
+            if let Some(transaction_metrics) = &self.transaction_metrics {
+                transaction_metrics.record(
+                    transaction.name.as_deref().unwrap_or_default(),
+                    &transaction.transaction_type,
+                    transaction.result.as_deref(),
+                    span_ctx.duration,
+                );
+            }
+
+            if visitor.request.is_some()
+                || visitor.response.is_some()
+                || visitor.message.is_some()
+                || user.is_some()
+            {
+                transaction.context = Some(TransactionContext {
+                    request: visitor.request.take(),
+                    response: visitor.response.take(),
+                    message: visitor.message.take(),
+                    user,
+                    ..Default::default()
+                });
+            }
+
             Batch::new(metadata, Some(json!(transaction)), None, None)
         } else {
             return;
         };
 
         self.client.send_batch(batch);
+
+        if self.breakdown_metrics.is_some() {
+            drop(extensions);
+
+            if let Some(parent) = span.parent() {
+                if let Some(parent_ctx) = parent.extensions_mut().get_mut::<SpanContext>() {
+                    parent_ctx.child_duration += span_ctx.duration;
+                }
+            }
+        }
     }
 }
 
 impl ApmLayer {
     pub(crate) fn new(mut config: Config, service_name: String) -> AnyResult<Self> {
-        let metadata = Metadata {
+        let mut metadata = Metadata {
             service: Service {
                 name: service_name,
                 version: config
@@ -242,24 +503,257 @@ impl ApmLayer {
                     .as_mut()
                     .and_then(|service| service.node.take()),
             },
-            process: config.process,
-            system: config.system,
+            process: Some(detect::process(config.process, config.suppress_argv)),
+            system: Some(detect::system(config.system)),
             user: config.user,
-            cloud: config.cloud,
+            cloud: None,
             labels: None,
         };
 
+        let sanitize_field_names = match config.sanitize_field_names {
+            Some(names) => names
+                .iter()
+                .map(|name| WildcardMatcher::new(name))
+                .collect(),
+            None => DEFAULT_SANITIZE_FIELD_NAMES
+                .iter()
+                .map(|name| WildcardMatcher::new(name))
+                .collect(),
+        };
+
+        let client = ApmClient::new(
+            config.apm_address,
+            config.authorization,
+            config.allow_invalid_certs,
+            config.root_cert_path,
+        )?;
+
+        let cloud_provider = config.cloud_provider;
+        let cloud_metadata_endpoints = config.cloud_metadata_endpoints.unwrap_or_default();
+        metadata.cloud = match config.cloud {
+            Some(cloud) => Some(cloud),
+            None if cloud_provider != CloudProvider::None => {
+                cloud::metadata_client().and_then(|metadata_client| {
+                    client
+                        .run_and_wait(
+                            cloud::detect(
+                                metadata_client,
+                                cloud_provider,
+                                cloud_metadata_endpoints,
+                            ),
+                            CLOUD_METADATA_TIMEOUT * 3,
+                        )
+                        .flatten()
+                })
+            }
+            None => None,
+        };
+
+        let metadata = json!(metadata);
+
+        let mut metric_sources: Vec<Arc<dyn MetricSource>> = Vec::new();
+
+        let breakdown_metrics = if config.breakdown_metrics {
+            let breakdown_metrics = Arc::new(BreakdownMetrics::default());
+            metric_sources.push(breakdown_metrics.clone());
+            Some(breakdown_metrics)
+        } else {
+            None
+        };
+
+        let custom_metrics = if config.custom_metrics {
+            let custom_metrics = Arc::new(CustomMetrics::default());
+            metric_sources.push(custom_metrics.clone());
+            Some(custom_metrics)
+        } else {
+            None
+        };
+
+        let transaction_metrics = if config.transaction_metrics {
+            let transaction_metrics = Arc::new(TransactionMetrics::default());
+            metric_sources.push(transaction_metrics.clone());
+            Some(transaction_metrics)
+        } else {
+            None
+        };
+
+        if config.system_metrics {
+            #[cfg(target_os = "linux")]
+            metric_sources.push(Arc::new(SystemMetrics::new()));
+        }
+
+        #[cfg(feature = "tokio-metrics")]
+        if let Some(handle) = config.tokio_runtime {
+            metric_sources.push(Arc::new(RuntimeMetrics::new(handle)));
+        }
+
+        if !metric_sources.is_empty() {
+            let metadata = metadata.clone();
+            client.send_periodically(
+                config.metrics_interval.unwrap_or(DEFAULT_METRICS_INTERVAL),
+                move || {
+                    let timestamp = SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap()
+                        .as_micros() as u64;
+
+                    let metricsets = metric_sources
+                        .iter()
+                        .flat_map(|source| source.collect(timestamp))
+                        .map(|metricset| json!(metricset))
+                        .collect::<Vec<_>>();
+
+                    if metricsets.is_empty() {
+                        None
+                    } else {
+                        Some(Batch::metricsets(metadata.clone(), metricsets))
+                    }
+                },
+            );
+        }
+
         Ok(ApmLayer {
-            client: ApmClient::new(
-                config.apm_address,
-                config.authorization,
-                config.allow_invalid_certs,
-                config.root_cert_path,
-            )?,
-            metadata: json!(metadata),
+            client,
+            metadata,
+            sanitize_field_names,
+            label_filter: LabelFilter::new(config.field_filter, config.target_field_filters),
+            error_level: config.error_level.unwrap_or(Level::ERROR),
+            error_filter: config.error_filter,
+            error_stacktraces: config.error_stacktraces,
+            panic_hook: config.panic_hook,
+            span_stacktrace_min_duration: config.span_stacktrace_min_duration,
+            error_deduplication_window: config.error_deduplication_window,
+            error_groups: Mutex::new(FxHashMap::default()),
+            log_level: config.log_level,
+            breakdown_metrics,
+            custom_metrics,
+            transaction_metrics,
         })
     }
 
+    fn report_error(
+        &self,
+        mut visitor: ApmVisitor,
+        param_message: Option<String>,
+        metadata: &'static tracing::Metadata<'static>,
+        event_trace: Option<EventTrace>,
+        is_panic: bool,
+    ) {
+        let mut exception = if is_panic {
+            Some(Exception {
+                message: visitor.label_string("message"),
+                exception_type: Some("panic".to_string()),
+                handled: Some(false),
+                ..Default::default()
+            })
+        } else if visitor.errors.is_empty() {
+            None
+        } else {
+            Some(visitor.errors.remove(0).into_exception(Some(true)))
+        };
+
+        if !is_panic {
+            if let Some(window) = self.error_deduplication_window {
+                // callsite identifies the message template, regardless of formatted values
+                let exception_type = exception
+                    .as_ref()
+                    .and_then(|exception| exception.exception_type.as_deref());
+                let grouping_key =
+                    fxhash::hash64(&(metadata.callsite(), metadata.target(), exception_type));
+
+                let mut error_groups = self
+                    .error_groups
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner);
+
+                match deduplicate_error(&mut error_groups, grouping_key, window, Instant::now()) {
+                    Some(0) => {}
+                    Some(suppressed) => {
+                        visitor
+                            .labels
+                            .insert(SUPPRESSED_ERRORS_LABEL.to_string(), json!(suppressed));
+                    }
+                    None => return,
+                }
+            }
+        }
+
+        let mut stacktrace = if self.error_stacktraces || is_panic {
+            Some(stacktrace::capture())
+        } else {
+            None
+        };
+
+        // prefer attaching the stack trace to the exception, if there is one
+        if let Some(exception) = &mut exception {
+            exception.stacktrace = stacktrace.take();
+        }
+
+        let mut error = Error {
+            id: random::<u128>().to_string(),
+            culprit: if is_panic {
+                visitor.label_string(PANIC_LOCATION_FIELD_NAME)
+            } else {
+                Some(culprit(metadata))
+            },
+            exception,
+            log: Some(Log {
+                level: Some(metadata.level().to_string()),
+                logger_name: Some(metadata.target().to_string()),
+                message: visitor.label_string("message").unwrap_or_default(),
+                param_message,
+                stacktrace,
+            }),
+            ..Default::default()
+        };
+
+        if let Some(event_trace) = event_trace {
+            error.trace_id = Some(event_trace.trace_id.to_string());
+            error.transaction_id = Some(event_trace.transaction_id.to_string());
+            error.parent_id = Some(event_trace.parent_id.to_string());
+            error.context = event_trace.user.map(|user| TransactionContext {
+                user: Some(user),
+                ..Default::default()
+            });
+        }
+
+        let metadata = self.create_metadata(&visitor, metadata);
+        let batch = Batch::new(metadata, None, None, Some(json!(error)));
+
+        if is_panic {
+            // the process might not survive the panic
+            self.client.send_batch_and_wait(batch, PANIC_FLUSH_TIMEOUT);
+        } else {
+            self.client.send_batch(batch);
+        }
+    }
+
+    fn report_log(
+        &self,
+        visitor: &ApmVisitor,
+        metadata: &'static tracing::Metadata<'static>,
+        event_trace: Option<&EventTrace>,
+    ) {
+        let log = LogRecord {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_micros() as u64,
+            message: visitor.label_string("message").unwrap_or_default(),
+            level: Some(metadata.level().to_string().to_lowercase()),
+            logger: Some(metadata.target().to_string()),
+            origin_file_name: metadata.file().map(String::from),
+            origin_file_line: metadata.line(),
+            trace_id: event_trace.map(|event_trace| event_trace.trace_id.to_string()),
+            transaction_id: event_trace.map(|event_trace| event_trace.transaction_id.to_string()),
+            span_id: event_trace.map(|event_trace| event_trace.parent_id.to_string()),
+            labels: log_labels(visitor, metadata.target(), &self.label_filter),
+        };
+
+        self.client
+            .send_batch(Batch::log(self.metadata.clone(), json!(log)));
+    }
+
     fn create_metadata(
         &self,
         visitor: &ApmVisitor,
@@ -267,8 +761,14 @@ impl ApmLayer {
     ) -> Value {
         let mut metadata = self.metadata.clone();
 
-        if !visitor.0.is_empty() {
-            metadata["labels"] = json!(visitor.0);
+        let labels = visitor
+            .labels
+            .iter()
+            .filter(|(name, _)| self.label_filter.allows(meta.target(), name))
+            .collect::<FxHashMap<_, _>>();
+
+        if !labels.is_empty() {
+            metadata["labels"] = json!(labels);
             metadata["labels"]["level"] = json!(meta.level().to_string());
             metadata["labels"]["target"] = json!(meta.target().to_string());
         }
@@ -276,3 +776,520 @@ impl ApmLayer {
         metadata
     }
 }
+
+/// Rate-limits errors with the same grouping key to one per window. Returns `None` if the error
+/// should be suppressed, or the number of errors suppressed since the last reported one.
+fn deduplicate_error(
+    error_groups: &mut FxHashMap<u64, ErrorGroup>,
+    grouping_key: u64,
+    window: Duration,
+    now: Instant,
+) -> Option<u64> {
+    if let Some(group) = error_groups.get_mut(&grouping_key) {
+        if now.saturating_duration_since(group.window_start) < window {
+            group.suppressed += 1;
+            return None;
+        }
+
+        let suppressed = group.suppressed;
+        group.window_start = now;
+        group.suppressed = 0;
+        return Some(suppressed);
+    }
+
+    if error_groups.len() >= MAX_ERROR_GROUPS {
+        // evicting in bulk keeps the cost of eviction amortized
+        let mut window_starts = error_groups
+            .iter()
+            .map(|(key, group)| (group.window_start, *key))
+            .collect::<Vec<_>>();
+        window_starts.sort_unstable();
+
+        for (_, key) in &window_starts[..error_groups.len() - MAX_ERROR_GROUPS / 2] {
+            error_groups.remove(key);
+        }
+    }
+
+    error_groups.insert(
+        grouping_key,
+        ErrorGroup {
+            window_start: now,
+            suppressed: 0,
+        },
+    );
+
+    Some(0)
+}
+
+/// Redacts labels and headers matching given field name patterns.
+fn sanitize(visitor: &mut ApmVisitor, field_names: &[WildcardMatcher]) {
+    for (name, value) in visitor.labels.iter_mut() {
+        if matches_any(field_names, name) {
+            *value = json!(REDACTED);
+        }
+    }
+
+    if let Some(headers) = visitor
+        .request
+        .as_mut()
+        .and_then(|request| request.headers.as_mut())
+    {
+        sanitize_headers(headers, field_names);
+    }
+
+    if let Some(headers) = visitor
+        .response
+        .as_mut()
+        .and_then(|response| response.headers.as_mut())
+    {
+        sanitize_headers(headers, field_names);
+    }
+
+    if let Some(headers) = visitor
+        .http
+        .as_mut()
+        .and_then(|http| http.response.as_mut())
+        .and_then(|response| response.headers.as_mut())
+    {
+        sanitize_headers(headers, field_names);
+    }
+
+    if let Some(headers) = visitor
+        .message
+        .as_mut()
+        .and_then(|message| message.headers.as_mut())
+    {
+        sanitize_headers(headers, field_names);
+    }
+}
+
+fn sanitize_headers(headers: &mut Headers, field_names: &[WildcardMatcher]) {
+    for (name, value) in headers.iter_mut() {
+        if matches_any(field_names, name) {
+            *value = REDACTED.to_string();
+        } else if name.eq_ignore_ascii_case("cookie") {
+            *value = sanitize_cookies(value, field_names);
+        }
+    }
+}
+
+/// Redacts values of individual cookies, e.g. `sessionid=[REDACTED]; theme=dark`.
+fn sanitize_cookies(cookies: &str, field_names: &[WildcardMatcher]) -> String {
+    cookies
+        .split(';')
+        .map(|cookie| {
+            let cookie = cookie.trim();
+            match cookie.split_once('=') {
+                Some((name, _)) if matches_any(field_names, name.trim()) => {
+                    format!("{}={}", name.trim(), REDACTED)
+                }
+                _ => cookie.to_string(),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+/// Returns labels of a log record. The message is sent separately, so it's excluded.
+fn log_labels(visitor: &ApmVisitor, target: &str, label_filter: &LabelFilter) -> Option<Tags> {
+    let labels = visitor
+        .labels
+        .iter()
+        .filter(|(name, _)| name.as_str() != "message" && label_filter.allows(target, name))
+        .map(|(name, value)| (name.clone(), value.clone()))
+        .collect::<Tags>();
+
+    if labels.is_empty() {
+        None
+    } else {
+        Some(labels)
+    }
+}
+
+/// Records a mark on the transaction enclosing given span. Marks in the form of `group.name` are
+/// put in given group, others in the `custom` group.
+fn record_mark<S>(mark: &str, parent_id: &Id, ctx: &Context<'_, S>)
+where
+    S: Subscriber + for<'lookup> LookupSpan<'lookup>,
+{
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_micros() as u64;
+
+    let (group, name) = mark.split_once('.').unwrap_or(("custom", mark));
+
+    let span = ctx.span(parent_id).expect("Span not found!");
+    let root = span
+        .scope()
+        .from_root()
+        .next()
+        .expect("Root span not found!");
+    let mut extensions = root.extensions_mut();
+
+    if let Some(transaction) = extensions.get_mut::<Transaction>() {
+        let offset = now.saturating_sub(transaction.timestamp.unwrap_or(now)) as f32 / 1000.;
+
+        transaction
+            .marks
+            .get_or_insert_with(Default::default)
+            .entry(group.to_string())
+            .or_default()
+            .insert(name.to_string(), offset);
+    }
+}
+
+/// Determines the outcome of a span or transaction: explicit `outcome` field takes precedence, then
+/// recorded errors, then HTTP status codes starting at `failure_status_code` are failures.
+fn outcome(visitor: &ApmVisitor, failed: bool, failure_status_code: i64) -> Outcome {
+    if let Some(outcome) = visitor.label_string(OUTCOME_FIELD_NAME) {
+        match outcome.to_lowercase().as_str() {
+            "success" => return Outcome::Success,
+            "failure" => return Outcome::Failure,
+            "unknown" => return Outcome::Unknown,
+            _ => {}
+        }
+    }
+
+    let error_recorded = match visitor.labels.get(ERROR_FIELD_NAME) {
+        Some(Value::Bool(error)) => *error,
+        Some(Value::Null) | None => false,
+        Some(_) => true,
+    };
+
+    if failed || error_recorded {
+        return Outcome::Failure;
+    }
+
+    match visitor.status_code() {
+        Some(status_code) if status_code >= failure_status_code => Outcome::Failure,
+        _ => Outcome::Success,
+    }
+}
+
+/// Describes the origin of an event as `module::path (file:line)`, falling back to the target.
+fn culprit(meta: &tracing::Metadata<'_>) -> String {
+    match (meta.module_path(), meta.file(), meta.line()) {
+        (Some(module_path), Some(file), Some(line)) => {
+            format!("{} ({}:{})", module_path, file, line)
+        }
+        (Some(module_path), _, _) => module_path.to_string(),
+        _ => meta.target().to_string(),
+    }
+}
+
+/// Installs a panic hook, which reports panics as error events in the context of the current span.
+/// Previously installed hook is called afterwards. The hook is installed at most once per process.
+fn install_panic_hook() {
+    static PANIC_HOOK: Once = Once::new();
+
+    PANIC_HOOK.call_once(|| {
+        let previous_hook = panic::take_hook();
+
+        panic::set_hook(Box::new(move |info| {
+            let payload = info.payload();
+            let message = payload
+                .downcast_ref::<&str>()
+                .copied()
+                .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+                .unwrap_or("Box<dyn Any>");
+
+            match info.location() {
+                Some(location) => tracing::error!(
+                    target: PANIC_TARGET,
+                    location = %location,
+                    "{}",
+                    message
+                ),
+                None => tracing::error!(target: PANIC_TARGET, "{}", message),
+            }
+
+            previous_hook(info);
+        }));
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::{FieldFilter, HTTP_STATUS_CODE_FIELD_NAME},
+        model::Request,
+    };
+
+    #[test]
+    fn test_sanitize() {
+        let field_names = DEFAULT_SANITIZE_FIELD_NAMES
+            .iter()
+            .map(|name| WildcardMatcher::new(name))
+            .collect::<Vec<_>>();
+
+        let mut headers = Headers::default();
+        headers.insert("Authorization".to_string(), "Bearer abc".to_string());
+        headers.insert(
+            "Cookie".to_string(),
+            "sessionid=abc; theme=dark;csrftoken=def".to_string(),
+        );
+        headers.insert("Accept".to_string(), "text/html".to_string());
+
+        let mut visitor = ApmVisitor {
+            request: Some(Request {
+                headers: Some(headers),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        visitor
+            .labels
+            .insert("password".to_string(), json!("secret"));
+        visitor.labels.insert("user_count".to_string(), json!(3));
+
+        sanitize(&mut visitor, &field_names);
+
+        assert_eq!(visitor.labels["password"], json!(REDACTED));
+        assert_eq!(visitor.labels["user_count"], json!(3));
+
+        let headers = visitor.request.unwrap().headers.unwrap();
+        assert_eq!(headers["Authorization"], REDACTED);
+        assert_eq!(
+            headers["Cookie"],
+            "sessionid=[REDACTED]; theme=dark; csrftoken=[REDACTED]"
+        );
+        assert_eq!(headers["Accept"], "text/html");
+    }
+
+    fn visitor(labels: &[(&str, Value)]) -> ApmVisitor {
+        let mut visitor = ApmVisitor::default();
+        for (name, value) in labels {
+            visitor.labels.insert(name.to_string(), value.clone());
+        }
+        visitor
+    }
+
+    #[test]
+    fn test_outcome() {
+        let explicit = visitor(&[(OUTCOME_FIELD_NAME, json!("success"))]);
+        assert!(matches!(outcome(&explicit, true, 400), Outcome::Success));
+
+        let explicit = visitor(&[(OUTCOME_FIELD_NAME, json!("FAILURE"))]);
+        assert!(matches!(outcome(&explicit, false, 400), Outcome::Failure));
+
+        let explicit = visitor(&[(OUTCOME_FIELD_NAME, json!("unknown"))]);
+        assert!(matches!(outcome(&explicit, false, 400), Outcome::Unknown));
+
+        // unrecognized explicit outcome falls through to other rules
+        let unrecognized = visitor(&[(OUTCOME_FIELD_NAME, json!("bogus"))]);
+        assert!(matches!(
+            outcome(&unrecognized, false, 400),
+            Outcome::Success
+        ));
+        assert!(matches!(
+            outcome(&unrecognized, true, 400),
+            Outcome::Failure
+        ));
+
+        let no_error = visitor(&[(ERROR_FIELD_NAME, json!(false))]);
+        assert!(matches!(outcome(&no_error, false, 400), Outcome::Success));
+
+        let error = visitor(&[(ERROR_FIELD_NAME, json!(true))]);
+        assert!(matches!(outcome(&error, false, 400), Outcome::Failure));
+
+        let error = visitor(&[(ERROR_FIELD_NAME, json!("connection reset"))]);
+        assert!(matches!(outcome(&error, false, 400), Outcome::Failure));
+
+        assert!(matches!(
+            outcome(&visitor(&[]), true, 400),
+            Outcome::Failure
+        ));
+        assert!(matches!(
+            outcome(&visitor(&[]), false, 400),
+            Outcome::Success
+        ));
+
+        let not_found = visitor(&[(HTTP_STATUS_CODE_FIELD_NAME, json!(404))]);
+        assert!(matches!(outcome(&not_found, false, 400), Outcome::Failure));
+        assert!(matches!(outcome(&not_found, false, 500), Outcome::Success));
+
+        let unavailable = visitor(&[(HTTP_STATUS_CODE_FIELD_NAME, json!(503))]);
+        assert!(matches!(
+            outcome(&unavailable, false, 500),
+            Outcome::Failure
+        ));
+    }
+
+    #[test]
+    fn test_deduplicate_error() {
+        let window = Duration::from_secs(10);
+        let start = Instant::now();
+        let mut error_groups = FxHashMap::default();
+
+        assert_eq!(
+            deduplicate_error(&mut error_groups, 1, window, start),
+            Some(0)
+        );
+
+        // identical errors within the window are suppressed and counted
+        let within = start + Duration::from_secs(1);
+        assert_eq!(
+            deduplicate_error(&mut error_groups, 1, window, within),
+            None
+        );
+        assert_eq!(
+            deduplicate_error(&mut error_groups, 1, window, within),
+            None
+        );
+
+        // other groups are independent
+        assert_eq!(
+            deduplicate_error(&mut error_groups, 2, window, within),
+            Some(0)
+        );
+
+        // first error after the window reports the suppressed count and starts a new window
+        let after = start + window;
+        assert_eq!(
+            deduplicate_error(&mut error_groups, 1, window, after),
+            Some(2)
+        );
+        assert_eq!(deduplicate_error(&mut error_groups, 1, window, after), None);
+        assert_eq!(
+            deduplicate_error(&mut error_groups, 1, window, after + window),
+            Some(1)
+        );
+    }
+
+    #[test]
+    fn test_deduplicate_error_limit() {
+        let window = Duration::from_secs(10);
+        let start = Instant::now();
+        let mut error_groups = FxHashMap::default();
+
+        for key in 0..(MAX_ERROR_GROUPS as u64 * 3) {
+            let now = start + Duration::from_millis(key);
+            assert_eq!(
+                deduplicate_error(&mut error_groups, key, window, now),
+                Some(0)
+            );
+            assert!(error_groups.len() <= MAX_ERROR_GROUPS);
+        }
+
+        // newest groups are kept
+        let last_key = MAX_ERROR_GROUPS as u64 * 3 - 1;
+        assert!(error_groups.contains_key(&last_key));
+        assert!(!error_groups.contains_key(&0));
+    }
+
+    #[test]
+    fn test_log_record() {
+        let visitor = visitor(&[("message", json!("user logged in")), ("count", json!(3))]);
+
+        let log = LogRecord {
+            timestamp: 1,
+            message: visitor.label_string("message").unwrap_or_default(),
+            level: Some("info".to_string()),
+            logger: Some("app::auth".to_string()),
+            origin_file_name: Some("src/auth.rs".to_string()),
+            origin_file_line: Some(42),
+            trace_id: Some("2".to_string()),
+            transaction_id: Some("3".to_string()),
+            span_id: Some("4".to_string()),
+            labels: log_labels(&visitor, "app::auth", &LabelFilter::new(None, Vec::new())),
+        };
+
+        assert_eq!(
+            json!(log),
+            json!({
+                "@timestamp": 1,
+                "message": "user logged in",
+                "log.level": "info",
+                "log.logger": "app::auth",
+                "log.origin.file.name": "src/auth.rs",
+                "log.origin.file.line": 42,
+                "trace.id": "2",
+                "transaction.id": "3",
+                "span.id": "4",
+                "labels": {"count": 3},
+            })
+        );
+    }
+
+    #[test]
+    fn test_breakdown_metrics() {
+        use std::thread::sleep;
+        use tracing_subscriber::prelude::*;
+
+        let layer = ApmLayer::new(
+            Config::new("http://127.0.0.1:1".to_string()).with_breakdown_metrics(true),
+            "test".to_string(),
+        )
+        .unwrap();
+        let breakdown_metrics = layer.breakdown_metrics.clone().unwrap();
+
+        tracing::subscriber::with_default(tracing_subscriber::registry().with(layer), || {
+            let transaction = tracing::info_span!("transaction");
+            let _transaction_guard = transaction.enter();
+            sleep(Duration::from_millis(20));
+
+            let span = tracing::info_span!("span");
+            let _span_guard = span.enter();
+            sleep(Duration::from_millis(40));
+        });
+
+        let metricsets = breakdown_metrics.collect(0);
+        assert_eq!(metricsets.len(), 2);
+
+        let self_time = |span_type: &str| {
+            let metricset = metricsets
+                .iter()
+                .find(|metricset| metricset.span.as_ref().unwrap().span_type == span_type)
+                .unwrap();
+            let transaction = metricset.transaction.as_ref().unwrap();
+            assert_eq!(transaction.name, "transaction");
+            assert_eq!(transaction.transaction_type, "custom");
+            assert_eq!(metricset.samples["span.self_time.count"].value, Some(1.));
+            Duration::from_micros(metricset.samples["span.self_time.sum.us"].value.unwrap() as u64)
+        };
+
+        // child span duration is excluded from the transaction self time
+        let app = self_time("app");
+        assert!(app >= Duration::from_millis(20) && app < Duration::from_millis(60));
+        assert!(self_time("custom") >= Duration::from_millis(40));
+    }
+
+    #[test]
+    fn test_custom_metrics() {
+        use tracing_subscriber::prelude::*;
+
+        let layer = ApmLayer::new(
+            Config::new("http://127.0.0.1:1".to_string())
+                .with_custom_metrics(true)
+                .with_field_filter(FieldFilter::new(Vec::new(), vec!["request_id".to_string()])),
+            "test".to_string(),
+        )
+        .unwrap();
+        let custom_metrics = layer.custom_metrics.clone().unwrap();
+
+        tracing::subscriber::with_default(tracing_subscriber::registry().with(layer), || {
+            tracing::info!(
+                metric.gauge.active_sessions = 7,
+                metric.counter.orders = 1,
+                region = "eu",
+                password = "secret",
+                request_id = 42,
+            );
+        });
+
+        let metricsets = custom_metrics.collect(0);
+        assert_eq!(metricsets.len(), 1);
+
+        // metric names matching sanitized field names are kept
+        let samples = &metricsets[0].samples;
+        assert_eq!(samples["active_sessions"].value, Some(7.));
+        assert_eq!(samples["orders"].value, Some(1.));
+
+        let tags = metricsets[0].tags.as_ref().unwrap();
+        assert_eq!(tags["region"], json!("eu"));
+        assert_eq!(tags["password"], json!(REDACTED));
+        assert!(!tags.contains_key("request_id"));
+    }
+}
@@ -7,10 +7,20 @@ use anyhow::Result as AnyResult;
 use crate::{config::Config, layer::ApmLayer};
 
 mod apm_client;
+mod cloud;
 pub mod config;
+mod detect;
+mod label_filter;
 pub mod layer;
+mod metrics;
 pub mod model;
+#[cfg(feature = "tokio-metrics")]
+mod runtime_metrics;
+mod stacktrace;
+#[cfg(target_os = "linux")]
+mod system_metrics;
 mod visitor;
+mod wildcard;
 
 /// Constructs a new telemetry layer for a given APM configuration.
 pub fn new_layer(service_name: String, config: Config) -> AnyResult<ApmLayer> {
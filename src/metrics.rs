@@ -0,0 +1,456 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Mutex, PoisonError},
+    time::Duration,
+};
+
+use fxhash::FxHashMap;
+use serde_json::{json, Value};
+
+use crate::{
+    config::{METRIC_COUNTER_FIELD_PREFIX, METRIC_GAUGE_FIELD_PREFIX},
+    model::{MetricSample, Metricset, MetricsetSpan, MetricsetTransaction, Samples, Tags},
+};
+
+/// Source of metricsets, collected periodically.
+pub(crate) trait MetricSource: Send + Sync {
+    /// Returns metricsets gathered since the last collection.
+    fn collect(&self, timestamp: u64) -> Vec<Metricset>;
+}
+
+/// Inserts a single value sample.
+#[inline]
+pub(crate) fn insert_sample(samples: &mut Samples, name: &str, value: f64) {
+    samples.insert(
+        name.to_string(),
+        MetricSample {
+            value: Some(value),
+            ..Default::default()
+        },
+    );
+}
+
+#[derive(PartialEq, Eq, Hash)]
+struct BreakdownKey {
+    transaction_name: String,
+    transaction_type: String,
+    span_type: String,
+    span_subtype: Option<String>,
+}
+
+#[derive(Default)]
+struct SelfTime {
+    count: u64,
+    sum: Duration,
+}
+
+/// Aggregates span self time per transaction and span type, as required by the breakdown chart.
+#[derive(Default)]
+pub(crate) struct BreakdownMetrics {
+    self_times: Mutex<FxHashMap<BreakdownKey, SelfTime>>,
+}
+
+impl BreakdownMetrics {
+    pub fn record(
+        &self,
+        transaction_name: &str,
+        transaction_type: &str,
+        span_type: &str,
+        span_subtype: Option<&str>,
+        self_time: Duration,
+    ) {
+        let key = BreakdownKey {
+            transaction_name: transaction_name.to_string(),
+            transaction_type: transaction_type.to_string(),
+            span_type: span_type.to_string(),
+            span_subtype: span_subtype.map(String::from),
+        };
+
+        let mut self_times = self
+            .self_times
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let entry = self_times.entry(key).or_default();
+        entry.count += 1;
+        entry.sum += self_time;
+    }
+}
+
+impl MetricSource for BreakdownMetrics {
+    fn collect(&self, timestamp: u64) -> Vec<Metricset> {
+        let self_times = std::mem::take(
+            &mut *self
+                .self_times
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner),
+        );
+
+        self_times
+            .into_iter()
+            .map(|(key, self_time)| {
+                let mut samples = Samples::default();
+                insert_sample(&mut samples, "span.self_time.count", self_time.count as f64);
+                insert_sample(
+                    &mut samples,
+                    "span.self_time.sum.us",
+                    self_time.sum.as_micros() as f64,
+                );
+
+                Metricset {
+                    timestamp,
+                    samples,
+                    transaction: Some(MetricsetTransaction {
+                        name: key.transaction_name,
+                        transaction_type: key.transaction_type,
+                        ..Default::default()
+                    }),
+                    span: Some(MetricsetSpan {
+                        span_type: key.span_type,
+                        subtype: key.span_subtype,
+                    }),
+                    ..Default::default()
+                }
+            })
+            .collect()
+    }
+}
+
+enum CustomMetricValue {
+    Counter(f64),
+    Gauge(f64),
+}
+
+impl CustomMetricValue {
+    fn value(&self) -> f64 {
+        match self {
+            CustomMetricValue::Counter(value) | CustomMetricValue::Gauge(value) => *value,
+        }
+    }
+}
+
+struct CustomMetricGroup {
+    tags: FxHashMap<String, Value>,
+    values: FxHashMap<String, CustomMetricValue>,
+}
+
+/// Aggregates counters and gauges recorded through events, grouped by the remaining event fields.
+#[derive(Default)]
+pub(crate) struct CustomMetrics {
+    groups: Mutex<FxHashMap<String, CustomMetricGroup>>,
+}
+
+impl CustomMetrics {
+    /// Returns true if given field name denotes a custom metric.
+    pub fn is_metric_field(name: &str) -> bool {
+        name.starts_with(METRIC_COUNTER_FIELD_PREFIX) || name.starts_with(METRIC_GAUGE_FIELD_PREFIX)
+    }
+
+    /// Records metric fields with given tags, except the message. Counters are summed and gauges
+    /// keep the last value until collected. Non-numeric metric values are ignored.
+    pub fn record(
+        &self,
+        metric_fields: FxHashMap<String, Value>,
+        mut tags: FxHashMap<String, Value>,
+    ) {
+        tags.remove("message");
+
+        let mut values = Vec::new();
+
+        for (name, value) in metric_fields {
+            if let Some(name) = name.strip_prefix(METRIC_COUNTER_FIELD_PREFIX) {
+                if let Some(value) = value.as_f64() {
+                    values.push((name.to_string(), CustomMetricValue::Counter(value)));
+                }
+            } else if let Some(name) = name.strip_prefix(METRIC_GAUGE_FIELD_PREFIX) {
+                if let Some(value) = value.as_f64() {
+                    values.push((name.to_string(), CustomMetricValue::Gauge(value)));
+                }
+            }
+        }
+
+        if values.is_empty() {
+            return;
+        }
+
+        // serialized sorted tags identify the group
+        let key = json!(tags.iter().collect::<BTreeMap<_, _>>()).to_string();
+
+        let mut groups = self.groups.lock().unwrap_or_else(PoisonError::into_inner);
+        let group = groups.entry(key).or_insert_with(|| CustomMetricGroup {
+            tags,
+            values: FxHashMap::default(),
+        });
+
+        for (name, value) in values {
+            match (group.values.get_mut(&name), value) {
+                (Some(CustomMetricValue::Counter(sum)), CustomMetricValue::Counter(value)) => {
+                    *sum += value;
+                }
+                (_, value) => {
+                    group.values.insert(name, value);
+                }
+            }
+        }
+    }
+}
+
+impl MetricSource for CustomMetrics {
+    fn collect(&self, timestamp: u64) -> Vec<Metricset> {
+        let groups =
+            std::mem::take(&mut *self.groups.lock().unwrap_or_else(PoisonError::into_inner));
+
+        groups
+            .into_values()
+            .map(|group| Metricset {
+                timestamp,
+                samples: group
+                    .values
+                    .into_iter()
+                    .map(|(name, value)| {
+                        (
+                            name,
+                            MetricSample {
+                                value: Some(value.value()),
+                                ..Default::default()
+                            },
+                        )
+                    })
+                    .collect(),
+                tags: if group.tags.is_empty() {
+                    None
+                } else {
+                    Some(group.tags.into_iter().collect::<Tags>())
+                },
+                ..Default::default()
+            })
+            .collect()
+    }
+}
+
+#[derive(PartialEq, Eq, Hash)]
+struct TransactionKey {
+    name: String,
+    transaction_type: String,
+    result: Option<String>,
+}
+
+#[derive(Default)]
+struct DurationHistogram {
+    /// Counts per bucket, keyed by bucket value in microseconds.
+    buckets: BTreeMap<u64, u64>,
+    count: u64,
+    sum: Duration,
+}
+
+/// Aggregates transaction durations per transaction name, type and result, so latency charts stay
+/// accurate regardless of which transactions are actually sent.
+#[derive(Default)]
+pub(crate) struct TransactionMetrics {
+    histograms: Mutex<FxHashMap<TransactionKey, DurationHistogram>>,
+}
+
+impl TransactionMetrics {
+    pub fn record(
+        &self,
+        name: &str,
+        transaction_type: &str,
+        result: Option<&str>,
+        duration: Duration,
+    ) {
+        let key = TransactionKey {
+            name: name.to_string(),
+            transaction_type: transaction_type.to_string(),
+            result: result.map(String::from),
+        };
+
+        let mut histograms = self
+            .histograms
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let histogram = histograms.entry(key).or_default();
+        *histogram
+            .buckets
+            .entry(bucket(duration.as_micros() as u64))
+            .or_default() += 1;
+        histogram.count += 1;
+        histogram.sum += duration;
+    }
+}
+
+impl MetricSource for TransactionMetrics {
+    fn collect(&self, timestamp: u64) -> Vec<Metricset> {
+        let histograms = std::mem::take(
+            &mut *self
+                .histograms
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner),
+        );
+
+        histograms
+            .into_iter()
+            .map(|(key, histogram)| {
+                let (values, counts) = histogram
+                    .buckets
+                    .into_iter()
+                    .map(|(value, count)| (value as f64, count))
+                    .unzip();
+
+                let mut samples = Samples::default();
+                samples.insert(
+                    "transaction.duration.histogram".to_string(),
+                    MetricSample {
+                        sample_type: Some("histogram".to_string()),
+                        values: Some(values),
+                        counts: Some(counts),
+                        ..Default::default()
+                    },
+                );
+                samples.insert(
+                    "transaction.duration.summary".to_string(),
+                    MetricSample {
+                        sample_type: Some("summary".to_string()),
+                        sum: Some(histogram.sum.as_micros() as f64),
+                        value_count: Some(histogram.count),
+                        ..Default::default()
+                    },
+                );
+
+                Metricset {
+                    timestamp,
+                    samples,
+                    transaction: Some(MetricsetTransaction {
+                        name: key.name,
+                        transaction_type: key.transaction_type,
+                        result: key.result,
+                    }),
+                    ..Default::default()
+                }
+            })
+            .collect()
+    }
+}
+
+/// Rounds a value to two significant digits, which bounds the number of buckets while keeping
+/// relative error within a few percent.
+fn bucket(value: u64) -> u64 {
+    let mut magnitude = 1;
+    while value / magnitude >= 100 {
+        magnitude *= 10;
+    }
+
+    (value + magnitude / 2) / magnitude * magnitude
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_breakdown_metrics() {
+        let metrics = BreakdownMetrics::default();
+        metrics.record("GET /", "request", "app", None, Duration::from_micros(100));
+        metrics.record("GET /", "request", "app", None, Duration::from_micros(50));
+        metrics.record(
+            "GET /",
+            "request",
+            "db",
+            Some("postgresql"),
+            Duration::from_micros(300),
+        );
+
+        let mut metricsets = metrics.collect(0);
+        metricsets.sort_by(|a, b| {
+            let (a, b) = (a.span.as_ref().unwrap(), b.span.as_ref().unwrap());
+            a.span_type.cmp(&b.span_type)
+        });
+        assert_eq!(metricsets.len(), 2);
+
+        let app = &metricsets[0];
+        assert_eq!(app.transaction.as_ref().unwrap().name, "GET /");
+        assert_eq!(app.span.as_ref().unwrap().subtype, None);
+        assert_eq!(app.samples["span.self_time.count"].value, Some(2.));
+        assert_eq!(app.samples["span.self_time.sum.us"].value, Some(150.));
+
+        let db = &metricsets[1];
+        assert_eq!(db.span.as_ref().unwrap().span_type, "db");
+        assert_eq!(
+            db.span.as_ref().unwrap().subtype.as_deref(),
+            Some("postgresql")
+        );
+        assert_eq!(db.samples["span.self_time.count"].value, Some(1.));
+        assert_eq!(db.samples["span.self_time.sum.us"].value, Some(300.));
+
+        assert!(metrics.collect(0).is_empty());
+    }
+
+    #[test]
+    fn test_custom_metrics() {
+        let metrics = CustomMetrics::default();
+        let record = |counter: i64, gauge: i64, region: &str| {
+            let mut fields = FxHashMap::default();
+            fields.insert("metric.counter.orders".to_string(), json!(counter));
+            fields.insert("metric.gauge.queue_depth".to_string(), json!(gauge));
+
+            let mut tags = FxHashMap::default();
+            tags.insert("region".to_string(), json!(region));
+            tags.insert("message".to_string(), json!("ignored"));
+
+            metrics.record(fields, tags);
+        };
+
+        record(1, 42, "eu");
+        record(2, 10, "eu");
+        record(5, 1, "us");
+
+        let mut metricsets = metrics.collect(0);
+        metricsets.sort_by_key(|metricset| metricset.samples["orders"].value.unwrap() as i64);
+        assert_eq!(metricsets.len(), 2);
+
+        let eu = &metricsets[0];
+        assert_eq!(eu.samples["orders"].value, Some(3.));
+        assert_eq!(eu.samples["queue_depth"].value, Some(10.));
+        assert_eq!(eu.tags.as_ref().unwrap()["region"], json!("eu"));
+        assert!(!eu.tags.as_ref().unwrap().contains_key("message"));
+
+        assert!(metrics.collect(0).is_empty());
+    }
+
+    #[test]
+    fn test_transaction_metrics() {
+        assert_eq!(bucket(0), 0);
+        assert_eq!(bucket(99), 99);
+        assert_eq!(bucket(1234), 1200);
+        assert_eq!(bucket(1250), 1300);
+
+        let metrics = TransactionMetrics::default();
+        metrics.record(
+            "GET /",
+            "request",
+            Some("HTTP 2xx"),
+            Duration::from_micros(1210),
+        );
+        metrics.record(
+            "GET /",
+            "request",
+            Some("HTTP 2xx"),
+            Duration::from_micros(1190),
+        );
+        metrics.record(
+            "GET /",
+            "request",
+            Some("HTTP 2xx"),
+            Duration::from_micros(5000),
+        );
+
+        let metricsets = metrics.collect(0);
+        assert_eq!(metricsets.len(), 1);
+
+        let histogram = &metricsets[0].samples["transaction.duration.histogram"];
+        assert_eq!(histogram.values, Some(vec![1200., 5000.]));
+        assert_eq!(histogram.counts, Some(vec![2, 1]));
+
+        let summary = &metricsets[0].samples["transaction.duration.summary"];
+        assert_eq!(summary.sum, Some(7400.));
+        assert_eq!(summary.value_count, Some(3));
+    }
+}
@@ -40,6 +40,11 @@ impl valuable::Visit for VisitHeaders {
     }
 }
 
+#[cfg(not(feature = "valuable"))]
+pub type Samples = FxHashMap<String, MetricSample>;
+#[cfg(feature = "valuable")]
+pub type Samples = HashMap<String, MetricSample>;
+
 #[cfg(not(feature = "valuable"))]
 type Marks = FxHashMap<String, FxHashMap<String, f32>>;
 #[cfg(feature = "valuable")]
@@ -572,7 +577,6 @@ pub struct TransactionContext {
 
 #[derive(Serialize, Debug)]
 #[serde(rename_all = "lowercase")]
-#[allow(dead_code)]
 pub enum Outcome {
     Success,
     Failure,
@@ -894,6 +898,24 @@ pub struct Span {
     pub duration: f32,
     pub name: String,
     pub sync: Option<bool>,
+    pub stacktrace: Option<Vec<StacktraceFrame>>,
+}
+
+/// Single stack frame, most recent call first.
+#[derive(Default, Serialize, Debug)]
+pub struct StacktraceFrame {
+    /// Absolute path of the source file.
+    pub abs_path: Option<String>,
+    /// Name of the source file.
+    pub filename: Option<String>,
+    /// Fully qualified function name.
+    pub function: Option<String>,
+    /// Module path of the function.
+    pub module: Option<String>,
+    pub lineno: Option<u32>,
+    pub colno: Option<u32>,
+    /// Whether the frame comes from a library, as opposed to application code.
+    pub library_frame: Option<bool>,
 }
 
 #[derive(Default, Serialize, Debug)]
@@ -902,6 +924,31 @@ pub struct Log {
     pub logger_name: Option<String>,
     pub message: String,
     pub param_message: Option<String>,
+    pub stacktrace: Option<Vec<StacktraceFrame>>,
+}
+
+/// Log record, correlated with the trace it has been recorded in.
+#[derive(Default, Serialize, Debug)]
+pub struct LogRecord {
+    /// Recorded time, in microseconds since Unix epoch.
+    #[serde(rename = "@timestamp")]
+    pub timestamp: u64,
+    pub message: String,
+    #[serde(rename = "log.level")]
+    pub level: Option<String>,
+    #[serde(rename = "log.logger")]
+    pub logger: Option<String>,
+    #[serde(rename = "log.origin.file.name")]
+    pub origin_file_name: Option<String>,
+    #[serde(rename = "log.origin.file.line")]
+    pub origin_file_line: Option<u32>,
+    #[serde(rename = "trace.id")]
+    pub trace_id: Option<String>,
+    #[serde(rename = "transaction.id")]
+    pub transaction_id: Option<String>,
+    #[serde(rename = "span.id")]
+    pub span_id: Option<String>,
+    pub labels: Option<Tags>,
 }
 
 #[derive(Default, Serialize, Debug)]
@@ -912,6 +959,9 @@ pub struct Exception {
     #[serde(rename = "type")]
     pub exception_type: Option<String>,
     pub handled: Option<bool>,
+    /// Errors which caused this exception, most immediate first.
+    pub cause: Option<Vec<Exception>>,
+    pub stacktrace: Option<Vec<StacktraceFrame>>,
 }
 
 #[derive(Default, Serialize, Debug)]
@@ -934,6 +984,56 @@ pub struct Error {
     pub log: Option<Log>,
 }
 
+/// Single metric sample.
+#[derive(Default, Serialize, Debug)]
+pub struct MetricSample {
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    pub sample_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value: Option<f64>,
+    /// Histogram bucket values.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub values: Option<Vec<f64>>,
+    /// Histogram bucket counts, matching `values`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub counts: Option<Vec<u64>>,
+    /// Summary sum.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sum: Option<f64>,
+    /// Summary value count.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value_count: Option<u64>,
+}
+
+/// Transaction a metricset relates to.
+#[derive(Default, Serialize, Debug)]
+pub struct MetricsetTransaction {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub transaction_type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<String>,
+}
+
+/// Span a metricset relates to.
+#[derive(Default, Serialize, Debug)]
+pub struct MetricsetSpan {
+    #[serde(rename = "type")]
+    pub span_type: String,
+    pub subtype: Option<String>,
+}
+
+/// Set of metric samples sharing the same timestamp and tags.
+#[derive(Default, Serialize, Debug)]
+pub struct Metricset {
+    /// Recorded time, in microseconds since Unix epoch.
+    pub timestamp: u64,
+    pub samples: Samples,
+    pub tags: Option<Tags>,
+    pub transaction: Option<MetricsetTransaction>,
+    pub span: Option<MetricsetSpan>,
+}
+
 #[cfg(all(test, feature = "valuable"))]
 mod tests {
     use super::*;
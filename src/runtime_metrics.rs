@@ -0,0 +1,127 @@
+use std::{
+    sync::{Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+
+use tokio::runtime::Handle;
+
+use crate::{
+    metrics::{insert_sample, MetricSource},
+    model::{Metricset, Samples},
+};
+
+/// Collects metrics of a tokio runtime, e.g. worker busy ratio, queue depths and task counts.
+pub(crate) struct RuntimeMetrics {
+    handle: Handle,
+    previous_busy_duration: Mutex<Option<(Instant, Duration)>>,
+}
+
+impl RuntimeMetrics {
+    pub fn new(handle: Handle) -> Self {
+        let busy_duration = total_busy_duration(&handle).map(|duration| (Instant::now(), duration));
+
+        RuntimeMetrics {
+            handle,
+            previous_busy_duration: Mutex::new(busy_duration),
+        }
+    }
+}
+
+impl MetricSource for RuntimeMetrics {
+    fn collect(&self, timestamp: u64) -> Vec<Metricset> {
+        let metrics = self.handle.metrics();
+        let num_workers = metrics.num_workers();
+
+        let mut samples = Samples::default();
+        insert_sample(
+            &mut samples,
+            "tokio.runtime.workers.count",
+            num_workers as f64,
+        );
+        insert_sample(
+            &mut samples,
+            "tokio.runtime.tasks.alive.count",
+            metrics.num_alive_tasks() as f64,
+        );
+        insert_sample(
+            &mut samples,
+            "tokio.runtime.global_queue.depth",
+            metrics.global_queue_depth() as f64,
+        );
+
+        if let Some(busy_duration) = total_busy_duration(&self.handle) {
+            let now = Instant::now();
+            let previous = self
+                .previous_busy_duration
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .replace((now, busy_duration));
+
+            if let Some((previous_timestamp, previous_busy_duration)) = previous {
+                let elapsed =
+                    now.saturating_duration_since(previous_timestamp) * num_workers as u32;
+                if !elapsed.is_zero() {
+                    insert_sample(
+                        &mut samples,
+                        "tokio.runtime.workers.busy.ratio",
+                        busy_duration
+                            .saturating_sub(previous_busy_duration)
+                            .as_secs_f64()
+                            / elapsed.as_secs_f64(),
+                    );
+                }
+            }
+        }
+
+        #[cfg(tokio_unstable)]
+        {
+            insert_sample(
+                &mut samples,
+                "tokio.runtime.workers.local_queue.depth",
+                (0..num_workers)
+                    .map(|worker| metrics.worker_local_queue_depth(worker))
+                    .sum::<usize>() as f64,
+            );
+            insert_sample(
+                &mut samples,
+                "tokio.runtime.blocking_threads.count",
+                metrics.num_blocking_threads() as f64,
+            );
+            insert_sample(
+                &mut samples,
+                "tokio.runtime.blocking_threads.idle.count",
+                metrics.num_idle_blocking_threads() as f64,
+            );
+            insert_sample(
+                &mut samples,
+                "tokio.runtime.blocking_queue.depth",
+                metrics.blocking_queue_depth() as f64,
+            );
+        }
+
+        vec![Metricset {
+            timestamp,
+            samples,
+            ..Default::default()
+        }]
+    }
+}
+
+/// Total time all workers have been busy. Only available on targets with 64-bit atomics.
+fn total_busy_duration(handle: &Handle) -> Option<Duration> {
+    #[cfg(target_has_atomic = "64")]
+    {
+        let metrics = handle.metrics();
+        Some(
+            (0..metrics.num_workers())
+                .map(|worker| metrics.worker_total_busy_duration(worker))
+                .sum(),
+        )
+    }
+
+    #[cfg(not(target_has_atomic = "64"))]
+    {
+        let _ = handle;
+        None
+    }
+}
@@ -0,0 +1,158 @@
+use std::{backtrace::Backtrace, path::Path};
+
+use crate::model::StacktraceFrame;
+
+/// Function prefixes of frames belonging to the standard library or tracing machinery, which are
+/// skipped at the top of the stack, so it starts at the code which caused the capture.
+const INTERNAL_FUNCTION_PREFIXES: &[&str] = &[
+    "std::",
+    "core::",
+    "alloc::",
+    "<std::",
+    "<core::",
+    "<alloc::",
+    "__rust",
+    "rust_begin_unwind",
+    "tracing::",
+    "tracing_core::",
+    "tracing_subscriber::",
+    "tracing_elastic_apm::",
+    "<tracing",
+];
+
+/// Function prefixes of frames coming from the standard library or common dependencies.
+const LIBRARY_FUNCTION_PREFIXES: &[&str] = &[
+    "std::", "core::", "alloc::", "<std::", "<core::", "<alloc::", "tokio::", "<tokio::",
+];
+
+/// Path fragments of files coming from the standard library or external crates.
+const LIBRARY_PATH_FRAGMENTS: &[&str] = &["/rustc/", "/.cargo/registry/", "/.cargo/git/"];
+
+/// Captures current stack trace in APM format, skipping internal frames on top.
+pub(crate) fn capture() -> Vec<StacktraceFrame> {
+    parse(&Backtrace::force_capture().to_string())
+}
+
+/// Parses the `Display` representation of `std::backtrace::Backtrace`.
+pub(crate) fn parse(backtrace: &str) -> Vec<StacktraceFrame> {
+    let mut frames: Vec<StacktraceFrame> = Vec::new();
+
+    for line in backtrace.lines() {
+        let line = line.trim();
+
+        if let Some(location) = line.strip_prefix("at ") {
+            if let Some(frame) = frames.last_mut() {
+                set_location(frame, location);
+            }
+        } else if let Some((index, function)) = line.split_once(": ") {
+            if index.chars().all(|c| c.is_ascii_digit()) {
+                frames.push(new_frame(function));
+            }
+        }
+    }
+
+    let internal = frames
+        .iter()
+        .take_while(|frame| {
+            frame
+                .function
+                .as_deref()
+                .map(|function| starts_with_any(function, INTERNAL_FUNCTION_PREFIXES))
+                .unwrap_or(true)
+        })
+        .count();
+
+    frames.drain(..internal);
+    frames
+}
+
+fn new_frame(function: &str) -> StacktraceFrame {
+    let function = strip_hash(function);
+    let module = if function.starts_with('<') {
+        None
+    } else {
+        function
+            .rsplit_once("::")
+            .map(|(module, _)| module.to_string())
+    };
+
+    StacktraceFrame {
+        function: Some(function.to_string()),
+        module,
+        library_frame: Some(starts_with_any(function, LIBRARY_FUNCTION_PREFIXES)),
+        ..Default::default()
+    }
+}
+
+fn set_location(frame: &mut StacktraceFrame, location: &str) {
+    let mut parts = location.rsplitn(3, ':');
+    let colno = parts.next().and_then(|colno| colno.parse().ok());
+    let lineno = parts.next().and_then(|lineno| lineno.parse().ok());
+    let path = match (parts.next(), lineno) {
+        (Some(path), Some(_)) => path,
+        _ => location,
+    };
+
+    frame.filename = Path::new(path)
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned());
+    frame.abs_path = Some(path.to_string());
+    frame.lineno = lineno;
+    frame.colno = colno;
+
+    if LIBRARY_PATH_FRAGMENTS
+        .iter()
+        .any(|fragment| path.contains(fragment))
+    {
+        frame.library_frame = Some(true);
+    }
+}
+
+fn strip_hash(function: &str) -> &str {
+    match function.rsplit_once("::h") {
+        Some((name, hash)) if hash.len() == 16 && hash.chars().all(|c| c.is_ascii_hexdigit()) => {
+            name
+        }
+        _ => function,
+    }
+}
+
+#[inline]
+fn starts_with_any(value: &str, prefixes: &[&str]) -> bool {
+    prefixes.iter().any(|prefix| value.starts_with(prefix))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BACKTRACE: &str = "   0: std::backtrace::Backtrace::force_capture
+             at /rustc/59807616e1fa2540724bfbac14d7976d7e4a3860/library/std/src/backtrace.rs:312:9
+   1: <tracing_subscriber::layer::layered::Layered<L,S> as tracing_core::subscriber::Subscriber>::event
+             at /root/.cargo/registry/src/index.crates.io-1949cf8c6b5b557f/tracing-subscriber-0.3.23/src/layer/layered.rs:153:9
+   2: app::handler::process::h0123456789abcdef
+             at ./src/handler.rs:42:13
+   3: tokio::runtime::task::core::Core<T,S>::poll
+             at /root/.cargo/registry/src/index.crates.io-1949cf8c6b5b557f/tokio-1.53.3/src/runtime/task/core.rs:365:24
+   4: <unknown>
+";
+
+    #[test]
+    fn test_parse() {
+        let frames = parse(BACKTRACE);
+        assert_eq!(frames.len(), 3);
+
+        assert_eq!(frames[0].function.as_deref(), Some("app::handler::process"));
+        assert_eq!(frames[0].module.as_deref(), Some("app::handler"));
+        assert_eq!(frames[0].filename.as_deref(), Some("handler.rs"));
+        assert_eq!(frames[0].abs_path.as_deref(), Some("./src/handler.rs"));
+        assert_eq!(frames[0].lineno, Some(42));
+        assert_eq!(frames[0].colno, Some(13));
+        assert_eq!(frames[0].library_frame, Some(false));
+
+        assert_eq!(frames[1].library_frame, Some(true));
+
+        assert_eq!(frames[2].function.as_deref(), Some("<unknown>"));
+        assert_eq!(frames[2].filename, None);
+    }
+}
@@ -0,0 +1,170 @@
+use std::{
+    fs,
+    sync::{Mutex, PoisonError},
+};
+
+use crate::{
+    detect::split_process_stat,
+    metrics::{insert_sample, MetricSource},
+    model::{Metricset, Samples},
+};
+
+#[derive(Clone, Copy)]
+struct CpuTimes {
+    /// Total time spent by all CPUs, in clock ticks.
+    total: u64,
+    /// Time spent idle by all CPUs, in clock ticks.
+    idle: u64,
+    /// Time spent by this process, in clock ticks.
+    process: u64,
+}
+
+/// Collects process and system CPU and memory metrics from `/proc`. Only available on Linux.
+pub(crate) struct SystemMetrics {
+    previous_cpu_times: Mutex<Option<CpuTimes>>,
+}
+
+impl SystemMetrics {
+    pub fn new() -> Self {
+        // initial reading allows reporting cpu usage with the first collection
+        SystemMetrics {
+            previous_cpu_times: Mutex::new(Self::read_cpu_times()),
+        }
+    }
+
+    fn read_cpu_times() -> Option<CpuTimes> {
+        let (total, idle) = parse_system_cpu(&fs::read_to_string("/proc/stat").ok()?)?;
+        let process = parse_process_cpu(&fs::read_to_string("/proc/self/stat").ok()?)?;
+
+        Some(CpuTimes {
+            total,
+            idle,
+            process,
+        })
+    }
+}
+
+impl MetricSource for SystemMetrics {
+    fn collect(&self, timestamp: u64) -> Vec<Metricset> {
+        let mut samples = Samples::default();
+
+        if let Some(cpu_times) = Self::read_cpu_times() {
+            let previous = self
+                .previous_cpu_times
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .replace(cpu_times);
+
+            // cpu usage needs two readings
+            if let Some(previous) = previous {
+                let total = cpu_times.total.saturating_sub(previous.total);
+                if total > 0 {
+                    let idle = cpu_times.idle.saturating_sub(previous.idle);
+                    let process = cpu_times.process.saturating_sub(previous.process);
+
+                    insert_sample(
+                        &mut samples,
+                        "system.cpu.total.norm.pct",
+                        1. - idle as f64 / total as f64,
+                    );
+                    insert_sample(
+                        &mut samples,
+                        "system.process.cpu.total.norm.pct",
+                        process as f64 / total as f64,
+                    );
+                }
+            }
+        }
+
+        if let Ok(meminfo) = fs::read_to_string("/proc/meminfo") {
+            if let Some(total) = parse_kb_value(&meminfo, "MemTotal:") {
+                insert_sample(&mut samples, "system.memory.total", total as f64);
+            }
+            if let Some(available) = parse_kb_value(&meminfo, "MemAvailable:") {
+                insert_sample(&mut samples, "system.memory.actual.free", available as f64);
+            }
+        }
+
+        if let Ok(status) = fs::read_to_string("/proc/self/status") {
+            if let Some(size) = parse_kb_value(&status, "VmSize:") {
+                insert_sample(&mut samples, "system.process.memory.size", size as f64);
+            }
+            if let Some(rss) = parse_kb_value(&status, "VmRSS:") {
+                insert_sample(&mut samples, "system.process.memory.rss.bytes", rss as f64);
+            }
+        }
+
+        if samples.is_empty() {
+            Vec::new()
+        } else {
+            vec![Metricset {
+                timestamp,
+                samples,
+                ..Default::default()
+            }]
+        }
+    }
+}
+
+/// Parses total and idle (including iowait) time of all CPUs from `/proc/stat`.
+fn parse_system_cpu(stat: &str) -> Option<(u64, u64)> {
+    let line = stat.lines().find(|line| line.starts_with("cpu "))?;
+    let times = line
+        .split_whitespace()
+        .skip(1)
+        .map(|value| value.parse::<u64>().ok())
+        .collect::<Option<Vec<_>>>()?;
+
+    // user, nice, system, idle, iowait, irq, softirq, steal; guest times are already included in
+    // user and nice
+    let total = times.iter().take(8).sum();
+    let idle = times.get(3)? + times.get(4).copied().unwrap_or_default();
+
+    Some((total, idle))
+}
+
+/// Parses user and system time of the process from `/proc/self/stat`.
+fn parse_process_cpu(stat: &str) -> Option<u64> {
+    let (_, fields) = split_process_stat(stat)?;
+    let mut fields = fields.skip(11);
+
+    let utime = fields.next()?.parse::<u64>().ok()?;
+    let stime = fields.next()?.parse::<u64>().ok()?;
+
+    Some(utime + stime)
+}
+
+/// Parses a `Name: value kB` line from `/proc/meminfo` or `/proc/self/status`, returning bytes.
+fn parse_kb_value(content: &str, name: &str) -> Option<u64> {
+    let line = content.lines().find(|line| line.starts_with(name))?;
+    let value = line[name.len()..]
+        .split_whitespace()
+        .next()?
+        .parse::<u64>()
+        .ok()?;
+
+    Some(value * 1024)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            parse_system_cpu("cpu  10 1 5 100 4 1 1 0 0 0\ncpu0 5 0 2 50 2 0 0 0 0 0\n"),
+            Some((122, 104))
+        );
+        assert_eq!(
+            parse_process_cpu(
+                "1234 (my (app) name) S 1 1234 1234 0 -1 4194560 500 0 0 0 42 8 0 0 20 0 4 0"
+            ),
+            Some(50)
+        );
+        assert_eq!(
+            parse_kb_value("MemTotal:       16303740 kB\nMemFree: 1 kB\n", "MemTotal:"),
+            Some(16303740 * 1024)
+        );
+    }
+}
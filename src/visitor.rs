@@ -1,17 +1,93 @@
-use std::fmt::Debug;
+use std::{convert::TryFrom, error::Error, fmt::Debug};
 
 use fxhash::FxHashMap;
 use serde::Serialize;
 use serde_json::{json, Value};
 use tracing::field::{Field, Visit};
 
-use crate::config::TRACE_ID_FIELD_NAME;
+use crate::{
+    config::{
+        HTTP_STATUS_CODE_FIELD_NAME, TRACE_ID_FIELD_NAME, USER_EMAIL_FIELD_NAME,
+        USER_ID_FIELD_NAME, USER_NAME_FIELD_NAME,
+    },
+    model::{Db, Exception, Http, Message, Request, Response, User},
+};
+
+/// Error recorded as a field value, along with its `source()` chain.
+pub(crate) struct RecordedError {
+    pub(crate) message: String,
+    pub(crate) error_type: Option<String>,
+    pub(crate) source: Option<Box<RecordedError>>,
+}
+
+impl RecordedError {
+    fn new(error: &(dyn Error + 'static)) -> Self {
+        RecordedError {
+            message: error.to_string(),
+            error_type: error_type_name(error),
+            source: error
+                .source()
+                .map(|source| Box::new(RecordedError::new(source))),
+        }
+    }
+
+    pub fn into_exception(self, handled: Option<bool>) -> Exception {
+        Exception {
+            message: Some(self.message),
+            exception_type: self.error_type,
+            handled,
+            cause: self.source.map(|source| vec![source.into_exception(None)]),
+            ..Default::default()
+        }
+    }
+}
 
 #[derive(Default)]
-#[repr(transparent)]
-pub(crate) struct ApmVisitor(pub(crate) FxHashMap<String, Value>);
+pub(crate) struct ApmVisitor {
+    pub(crate) labels: FxHashMap<String, Value>,
+    pub(crate) errors: Vec<RecordedError>,
+    pub(crate) request: Option<Request>,
+    pub(crate) response: Option<Response>,
+    pub(crate) db: Option<Db>,
+    pub(crate) http: Option<Http>,
+    pub(crate) message: Option<Message>,
+}
 
 impl Visit for ApmVisitor {
+    #[cfg(all(tracing_unstable, feature = "valuable"))]
+    fn record_value(&mut self, field: &Field, value: valuable::Value<'_>) {
+        use crate::config::{
+            DB_FIELD_NAME, HTTP_FIELD_NAME, HTTP_REQUEST_FIELD_NAME, HTTP_RESPONSE_FIELD_NAME,
+            MESSAGE_FIELD_NAME,
+        };
+
+        match field.name() {
+            HTTP_REQUEST_FIELD_NAME => {
+                valuable::visit(&value, self.request.get_or_insert_with(Default::default))
+            }
+            HTTP_RESPONSE_FIELD_NAME => {
+                valuable::visit(&value, self.response.get_or_insert_with(Default::default))
+            }
+            DB_FIELD_NAME => valuable::visit(&value, self.db.get_or_insert_with(Default::default)),
+            HTTP_FIELD_NAME => {
+                valuable::visit(&value, self.http.get_or_insert_with(Default::default))
+            }
+            MESSAGE_FIELD_NAME => {
+                valuable::visit(&value, self.message.get_or_insert_with(Default::default))
+            }
+            _ => self.record_debug(field, &value),
+        }
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        // JSON has no representation for NaN or infinities
+        if value.is_finite() {
+            self.insert_value(field, value);
+        } else {
+            self.insert_value(field, value.to_string());
+        }
+    }
+
     fn record_i64(&mut self, field: &Field, value: i64) {
         self.insert_value(field, value);
     }
@@ -20,6 +96,20 @@ impl Visit for ApmVisitor {
         self.insert_value(field, value);
     }
 
+    fn record_i128(&mut self, field: &Field, value: i128) {
+        match i64::try_from(value) {
+            Ok(value) => self.insert_value(field, value),
+            Err(_) => self.insert_value(field, value.to_string()),
+        }
+    }
+
+    fn record_u128(&mut self, field: &Field, value: u128) {
+        match u64::try_from(value) {
+            Ok(value) => self.insert_value(field, value),
+            Err(_) => self.insert_value(field, value.to_string()),
+        }
+    }
+
     fn record_bool(&mut self, field: &Field, value: bool) {
         self.insert_value(field, value);
     }
@@ -28,18 +118,115 @@ impl Visit for ApmVisitor {
         self.insert_value(field, value);
     }
 
+    fn record_error(&mut self, field: &Field, value: &(dyn Error + 'static)) {
+        // errors are flattened into a single label with the whole cause chain
+        let mut message = value.to_string();
+        let mut source = value.source();
+        while let Some(cause) = source {
+            message.push_str(": ");
+            message.push_str(&cause.to_string());
+            source = cause.source();
+        }
+
+        self.insert_value(field, message);
+        self.errors.push(RecordedError::new(value));
+    }
+
     fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
         self.insert_value(field, format!("{:?}", value));
     }
 }
 
 impl ApmVisitor {
+    /// Returns user context from reserved user fields, if any has been recorded.
+    pub fn user(&self) -> Option<User> {
+        let id = self.label_string(USER_ID_FIELD_NAME);
+        let email = self.label_string(USER_EMAIL_FIELD_NAME);
+        let username = self.label_string(USER_NAME_FIELD_NAME);
+
+        if id.is_none() && email.is_none() && username.is_none() {
+            None
+        } else {
+            Some(User {
+                id,
+                email,
+                username,
+            })
+        }
+    }
+
+    /// Removes reserved user fields from labels, returning them as user context.
+    pub fn take_user(&mut self) -> Option<User> {
+        let user = self.user();
+
+        self.labels.remove(USER_ID_FIELD_NAME);
+        self.labels.remove(USER_EMAIL_FIELD_NAME);
+        self.labels.remove(USER_NAME_FIELD_NAME);
+
+        user
+    }
+
+    /// Returns recorded HTTP response status code, if any.
+    pub fn status_code(&self) -> Option<i64> {
+        self.response
+            .as_ref()
+            .and_then(|response| response.status_code)
+            .or_else(|| self.http.as_ref().and_then(|http| http.status_code))
+            .or_else(|| {
+                self.http
+                    .as_ref()
+                    .and_then(|http| http.response.as_ref())
+                    .and_then(|response| response.status_code)
+            })
+            .map(i64::from)
+            .or_else(|| {
+                self.labels
+                    .get(HTTP_STATUS_CODE_FIELD_NAME)
+                    .and_then(Value::as_i64)
+            })
+    }
+
+    /// Returns the value of a label as a string, without quoting string values.
+    pub fn label_string(&self, name: &str) -> Option<String> {
+        self.labels.get(name).map(|value| match value {
+            Value::String(value) => value.clone(),
+            value => value.to_string(),
+        })
+    }
+
+    /// Removes a label, returning its value as a string, without quoting string values.
+    pub fn take_label_string(&mut self, name: &str) -> Option<String> {
+        let value = self.label_string(name);
+        self.labels.remove(name);
+        value
+    }
+
     #[inline]
     fn insert_value<T>(&mut self, field: &Field, value: T)
     where
         T: Serialize,
     {
-        self.0.insert(field.name().to_string(), json!(value));
+        self.labels.insert(field.name().to_string(), json!(value));
+    }
+}
+
+/// Type name of an error, if it can be determined reliably. Only common std errors are recognized,
+/// since `Debug` output of other errors doesn't necessarily start with the type name.
+fn error_type_name(error: &(dyn Error + 'static)) -> Option<String> {
+    if error.is::<std::io::Error>() {
+        Some("std::io::Error".to_string())
+    } else if error.is::<std::fmt::Error>() {
+        Some("std::fmt::Error".to_string())
+    } else if error.is::<std::num::ParseIntError>() {
+        Some("std::num::ParseIntError".to_string())
+    } else if error.is::<std::num::ParseFloatError>() {
+        Some("std::num::ParseFloatError".to_string())
+    } else if error.is::<std::str::Utf8Error>() {
+        Some("std::str::Utf8Error".to_string())
+    } else if error.is::<std::string::FromUtf8Error>() {
+        Some("std::string::FromUtf8Error".to_string())
+    } else {
+        None
     }
 }
 
@@ -64,3 +251,54 @@ impl Visit for TraceIdVisitor {
 
     fn record_debug(&mut self, _field: &Field, _value: &dyn Debug) {}
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fmt::{Display, Formatter};
+
+    use super::*;
+
+    #[derive(Debug)]
+    struct Unauthorized(std::io::Error);
+
+    impl Display for Unauthorized {
+        fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+            write!(f, "unauthorized")
+        }
+    }
+
+    impl Error for Unauthorized {
+        fn source(&self) -> Option<&(dyn Error + 'static)> {
+            Some(&self.0)
+        }
+    }
+
+    #[test]
+    fn test_error_type_name() {
+        let io_error = std::io::Error::other("denied");
+        assert_eq!(
+            error_type_name(&io_error).as_deref(),
+            Some("std::io::Error")
+        );
+        assert_eq!(
+            error_type_name(&Unauthorized(std::io::Error::other("denied"))),
+            None
+        );
+    }
+
+    #[test]
+    fn test_into_exception() {
+        let error = Unauthorized(std::io::Error::other("denied"));
+        let exception = RecordedError::new(&error).into_exception(Some(true));
+
+        assert_eq!(exception.message.as_deref(), Some("unauthorized"));
+        assert_eq!(exception.exception_type, None);
+        assert_eq!(exception.handled, Some(true));
+
+        let cause = &exception.cause.unwrap()[0];
+        assert_eq!(cause.message.as_deref(), Some("denied"));
+        assert_eq!(cause.exception_type.as_deref(), Some("std::io::Error"));
+        assert_eq!(cause.handled, None);
+        assert!(cause.cause.is_none());
+    }
+}
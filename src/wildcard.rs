@@ -0,0 +1,65 @@
+/// Case-insensitive field name matcher supporting `*` wildcards.
+#[derive(Debug)]
+pub(crate) struct WildcardMatcher {
+    parts: Vec<String>,
+}
+
+impl WildcardMatcher {
+    pub fn new(pattern: &str) -> Self {
+        WildcardMatcher {
+            parts: pattern
+                .to_lowercase()
+                .split('*')
+                .map(String::from)
+                .collect(),
+        }
+    }
+
+    pub fn matches(&self, value: &str) -> bool {
+        let value = value.to_lowercase();
+
+        // split always yields at least one part
+        let (first, rest) = self.parts.split_first().unwrap();
+        let mut remaining = match value.strip_prefix(first.as_str()) {
+            Some(remaining) => remaining,
+            None => return false,
+        };
+
+        let (last, middle) = match rest.split_last() {
+            Some(parts) => parts,
+            None => return remaining.is_empty(),
+        };
+
+        for part in middle {
+            match remaining.find(part.as_str()) {
+                Some(index) => remaining = &remaining[index + part.len()..],
+                None => return false,
+            }
+        }
+
+        remaining.ends_with(last.as_str())
+    }
+}
+
+/// Returns true if any of the matchers matches given value.
+pub(crate) fn matches_any(matchers: &[WildcardMatcher], value: &str) -> bool {
+    matchers.iter().any(|matcher| matcher.matches(value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matches() {
+        assert!(WildcardMatcher::new("password").matches("Password"));
+        assert!(!WildcardMatcher::new("password").matches("password2"));
+        assert!(WildcardMatcher::new("*key").matches("api_key"));
+        assert!(!WildcardMatcher::new("*key").matches("keys"));
+        assert!(WildcardMatcher::new("*token*").matches("x-token-value"));
+        assert!(WildcardMatcher::new("*token*").matches("token"));
+        assert!(WildcardMatcher::new("a*b*c").matches("aXbYc"));
+        assert!(!WildcardMatcher::new("a*b*c").matches("aXcYb"));
+        assert!(WildcardMatcher::new("*").matches(""));
+    }
+}